@@ -0,0 +1,132 @@
+// MatchList::lookup against walking the rules in order, the way it
+// was done before the index, over 10k exact, suffix, wildcard and
+// regex rules interleaved
+//
+// cargo bench --bench index [-- <rules>]
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use lurkr::{dispatcher::Dispatcher, index::MatchList, matcher::Matcher};
+use regex::RegexBuilder;
+
+const LOOKUPS: usize = 2000;
+
+fn rule(position: usize) -> Matcher {
+    let rulename = format!("rule{}", position);
+    let dispatcher = Dispatcher::TCPDownstreamDispatcher {
+        downstreams: vec![],
+        splice: false,
+    };
+    match position % 4 {
+        0 => Matcher::ExactMatcher {
+            rulename,
+            dispatcher,
+            http_dispatcher: None,
+            acl: None,
+            exact: format!("host{}.exact.test", position),
+        },
+        1 => Matcher::SuffixMatcher {
+            rulename,
+            dispatcher,
+            http_dispatcher: None,
+            acl: None,
+            suffix: format!("zone{}.suffix.test", position),
+        },
+        2 => Matcher::WildcardMatcher {
+            rulename,
+            dispatcher,
+            http_dispatcher: None,
+            acl: None,
+            wildcard: format!(".wild{}.test", position),
+        },
+        _ => Matcher::RegexMatcher {
+            rulename,
+            dispatcher,
+            http_dispatcher: None,
+            acl: None,
+            regex: RegexBuilder::new(&format!(r"^api{}-[a-z]+\.regex\.test$", position))
+                .case_insensitive(true)
+                .build()
+                .unwrap(),
+        },
+    }
+}
+
+// a name each kind of rule answers, spread over the list, and misses
+fn names(rules: usize) -> Vec<String> {
+    (0..LOOKUPS)
+        .map(|n| {
+            let position = n * 7919 % rules;
+            match n % 5 {
+                4 => format!("nobody{}.example.org", n),
+                _ => match position % 4 {
+                    0 => format!("host{}.exact.test", position),
+                    1 => format!("deep.www.zone{}.suffix.test", position),
+                    2 => format!("www.wild{}.test", position),
+                    _ => format!("api{}-eu.regex.test", position),
+                },
+            }
+        })
+        .collect()
+}
+
+fn time<'a>(names: &'a [String], mut lookup: impl FnMut(&'a str) -> Option<&'a str>) -> Duration {
+    let start = Instant::now();
+    for name in names {
+        black_box(lookup(black_box(name)));
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let rules: usize = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(10_000);
+    let mut matchers: Vec<Matcher> = (0..rules).map(rule).collect();
+    matchers.push(Matcher::UniversalMatcher {
+        rulename: "__default".to_string(),
+        dispatcher: Dispatcher::TCPDownstreamDispatcher {
+            downstreams: vec![],
+            splice: false,
+        },
+        http_dispatcher: None,
+        acl: None,
+    });
+    let start = Instant::now();
+    let list = MatchList::new(matchers).unwrap();
+    println!("{} rules, index built in {:?}", rules, start.elapsed());
+
+    let names = names(rules);
+    // same answers either way, or the comparison means nothing
+    for name in names.iter() {
+        assert_eq!(
+            list.lookup(name, None).map(|matcher| matcher.rulename()),
+            list.iter()
+                .find(|matcher| matcher.admits(name, None))
+                .map(|matcher| matcher.rulename()),
+            "{}",
+            name
+        );
+    }
+
+    let linear = time(&names, |name| {
+        list.iter()
+            .find(|matcher| matcher.admits(name, None))
+            .map(|matcher| matcher.rulename())
+    });
+    let indexed = time(&names, |name| {
+        list.lookup(name, None).map(|matcher| matcher.rulename())
+    });
+    for (how, took) in [("linear walk", linear), ("MatchList::lookup", indexed)] {
+        println!(
+            "{:>18}: {:>10.2?} per lookup ({} lookups)",
+            how,
+            took / LOOKUPS as u32,
+            LOOKUPS
+        );
+    }
+}
@@ -0,0 +1,112 @@
+// raw TCP passthrough, splice(2) against tokio::io::copy: a client pushes
+// bytes through tcp_proxy_stream on loopback to a sink that counts them
+//
+// cargo bench --bench splice [-- <MiB per run>]
+//
+// CPU is the whole process (client and sink included, which cost the
+// same either way), so the difference between the rows is the proxy's
+
+#[cfg(target_os = "linux")]
+mod bench {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    const CHUNK: usize = 256 * 1024;
+    const RUNS: usize = 3;
+
+    fn cpu_time() -> Duration {
+        let mut usage = unsafe { std::mem::zeroed::<libc::rusage>() };
+        unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+        let tv = |tv: libc::timeval| {
+            Duration::from_secs(tv.tv_sec as u64) + Duration::from_micros(tv.tv_usec as u64)
+        };
+        tv(usage.ru_utime) + tv(usage.ru_stime)
+    }
+
+    async fn sink(lsnr: TcpListener) -> u64 {
+        let (mut stream, _) = lsnr.accept().await.unwrap();
+        let mut buf = vec![0; CHUNK];
+        let mut total = 0;
+        loop {
+            match stream.read(&mut buf).await.unwrap() {
+                0 => return total,
+                read => total += read as u64,
+            }
+        }
+    }
+
+    async fn proxy(lsnr: TcpListener, downstream: SocketAddr, splice: bool) {
+        let (incoming, client) = lsnr.accept().await.unwrap();
+        let outgoing = TcpStream::connect(downstream).await.unwrap();
+        let conn = lurkr::track::Tracked::new(client);
+        lurkr::proxy::tcp_proxy_stream(incoming, outgoing, splice, &conn)
+            .await
+            .unwrap();
+    }
+
+    // wall time and process CPU for total bytes through the proxy
+    async fn run(total: u64, splice: bool) -> (Duration, Duration) {
+        let sink_lsnr = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_lsnr = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sink_addr = sink_lsnr.local_addr().unwrap();
+        let proxy_addr = proxy_lsnr.local_addr().unwrap();
+        let sunk = tokio::spawn(sink(sink_lsnr));
+        let proxied = tokio::spawn(proxy(proxy_lsnr, sink_addr, splice));
+
+        let chunk = vec![0x5a; CHUNK];
+        let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+        let (wall, cpu) = (Instant::now(), cpu_time());
+        let mut sent = 0;
+        while sent < total {
+            let len = CHUNK.min((total - sent) as usize);
+            client.write_all(&chunk[..len]).await.unwrap();
+            sent += len as u64;
+        }
+        client.shutdown().await.unwrap();
+        assert_eq!(sunk.await.unwrap(), total, "bytes went missing");
+        let elapsed = (wall.elapsed(), cpu_time() - cpu);
+        proxied.await.unwrap();
+        elapsed
+    }
+
+    pub fn main() {
+        let mib: u64 = std::env::args()
+            .skip(1)
+            .find_map(|arg| arg.parse().ok())
+            .unwrap_or(1024);
+        let total = mib * 1024 * 1024;
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        println!("{} MiB per run, best of {}", mib, RUNS);
+        for (name, splice) in [("tokio::io::copy", false), ("splice(2)", true)] {
+            let (wall, cpu) = (0..RUNS)
+                .map(|_| runtime.block_on(run(total, splice)))
+                .min()
+                .unwrap();
+            println!(
+                "{:>16}: {:>8.1} MiB/s, {:.3}s wall, {:.3}s cpu ({:.3} cpu s/GiB)",
+                name,
+                mib as f64 / wall.as_secs_f64(),
+                wall.as_secs_f64(),
+                cpu.as_secs_f64(),
+                cpu.as_secs_f64() * 1024.0 / mib as f64
+            );
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn main() {
+    bench::main()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    println!("splice(2) is linux-only, nothing to compare");
+}
@@ -0,0 +1,148 @@
+use std::{collections::BTreeMap, convert::Infallible};
+
+use http_body_util::Full;
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+
+use crate::track::DownstreamHealth;
+
+// GET  /connections  what's in flight right now
+// GET  /config       the parsed configuration, secrets redacted
+// GET  /matchers     the compiled MATCHLIST, in evaluation order
+// GET  /downstreams  every configured downstream and how connecting to it went
+// GET  /metrics      days until each loaded certificate expires, Prometheus-style
+// POST /reload       re-read the config file
+// POST /drain        stop accepting and wind down, same as SIGTERM
+pub async fn admin_listener() -> anyhow::Result<()> {
+    let cfg = crate::fullcfg();
+    let Some(admin) = &cfg.admin else {
+        return Ok(());
+    };
+
+    #[cfg(unix)]
+    if let Some(path) = &admin.unix_socket {
+        // a leftover socket from last time would make bind() sad, but
+        // anything else sitting there isn't ours to delete
+        use std::os::unix::fs::FileTypeExt;
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => {
+                return Err(anyhow::anyhow!(
+                    "admin unix_socket {} exists and isn't a socket, not removing it",
+                    path
+                ));
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+        let lsnr = tokio::net::UnixListener::bind(path)?;
+        tracing::info!("admin listening on {}", path);
+        loop {
+            let (stream, _) = lsnr.accept().await?;
+            tokio::spawn(serve(stream));
+        }
+    }
+
+    let final_addr = format!(
+        "{}:{}",
+        admin.addr.as_deref().unwrap_or("127.0.0.1"),
+        admin.port.unwrap_or(9338)
+    );
+    let lsnr = TcpListener::bind(&final_addr).await?;
+    tracing::info!("admin listening on {}", final_addr);
+    loop {
+        let (stream, _) = lsnr.accept().await?;
+        tokio::spawn(serve(stream));
+    }
+}
+
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(stream: S) {
+    if let Err(err) = http1::Builder::new()
+        .serve_connection(TokioIo::new(stream), service_fn(route))
+        .await
+    {
+        tracing::debug!("admin connection: {:?}", err);
+    }
+}
+
+async fn route(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    tracing::debug!("admin {} {}", req.method(), req.uri().path());
+    Ok(match (req.method(), req.uri().path()) {
+        (&Method::GET, "/connections") => json(&crate::track::snapshot()),
+        (&Method::GET, "/config") => json(&*crate::fullcfg()),
+        (&Method::GET, "/matchers") => json(&*crate::matchlist()),
+        (&Method::GET, "/downstreams") => json(&downstreams()),
+        (&Method::GET, "/metrics") => text(StatusCode::OK, crate::expiry::metrics()),
+        (&Method::POST, "/reload") => match tokio::task::spawn_blocking(crate::load).await {
+            Ok(Ok(())) => text(StatusCode::OK, "reloaded\n".to_string()),
+            Ok(Err(err)) => text(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("reload failed:\n{:#}", err),
+            ),
+            Err(err) => text(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("reload blew up: {}\n", err),
+            ),
+        },
+        (&Method::POST, "/drain") => {
+            tracing::info!("drain requested via admin");
+            crate::LISTENER_STOP.0.send(()).ok();
+            text(StatusCode::ACCEPTED, "draining\n".to_string())
+        }
+        _ => text(StatusCode::NOT_FOUND, "not found\n".to_string()),
+    })
+}
+
+// configured downstreams show up even if nobody has connected to them yet
+fn downstreams() -> BTreeMap<String, DownstreamHealth> {
+    let mut all = BTreeMap::<String, DownstreamHealth>::new();
+    for matcher in crate::matchlist().iter() {
+        let http_downstreams = matcher
+            .http_dispatcher()
+            .map_or(&[][..], |d| d.downstreams());
+        for downstream in matcher
+            .dispatcher()
+            .downstreams()
+            .iter()
+            .chain(http_downstreams)
+        {
+            all.entry(downstream.clone()).or_default();
+        }
+    }
+    for (downstream, health) in crate::track::DOWNSTREAMS.lock().unwrap().iter() {
+        all.insert(downstream.clone(), health.clone());
+    }
+    all
+}
+
+fn json<T: Serialize + ?Sized>(value: &T) -> Response<Full<Bytes>> {
+    match serde_json::to_vec_pretty(value) {
+        Ok(body) => Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap(),
+        Err(err) => text(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("couldn't serialize: {}\n", err),
+        ),
+    }
+}
+
+fn text(status: StatusCode, body: String) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/plain")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
@@ -0,0 +1,189 @@
+// TLS alert records put together by hand: seven bytes don't need
+// rustls's internal message types, which can change under us
+
+use anyhow::anyhow;
+use rustls::AlertDescription;
+use tokio::{
+    io::{self, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const RECORD_ALERT: u8 = 0x15;
+const RECORD_HANDSHAKE: u8 = 0x16;
+// what TLS 1.2 and 1.3 both put on records after the ClientHello
+const DEFAULT_RECORD_VERSION: u16 = 0x0303;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlertLevel {
+    Warning = 1,
+    Fatal = 2,
+}
+
+// RFC 8446 (and 5246) alert names, for configs to say which one
+const ALERTS: [(&str, u8); 27] = [
+    ("close_notify", 0),
+    ("unexpected_message", 10),
+    ("bad_record_mac", 20),
+    ("record_overflow", 22),
+    ("handshake_failure", 40),
+    ("bad_certificate", 42),
+    ("unsupported_certificate", 43),
+    ("certificate_revoked", 44),
+    ("certificate_expired", 45),
+    ("certificate_unknown", 46),
+    ("illegal_parameter", 47),
+    ("unknown_ca", 48),
+    ("access_denied", 49),
+    ("decode_error", 50),
+    ("decrypt_error", 51),
+    ("protocol_version", 70),
+    ("insufficient_security", 71),
+    ("internal_error", 80),
+    ("inappropriate_fallback", 86),
+    ("user_canceled", 90),
+    ("missing_extension", 109),
+    ("unsupported_extension", 110),
+    ("unrecognized_name", 112),
+    ("bad_certificate_status_response", 113),
+    ("unknown_psk_identity", 115),
+    ("certificate_required", 116),
+    ("no_application_protocol", 120),
+];
+
+pub fn by_name(name: &str) -> anyhow::Result<AlertDescription> {
+    ALERTS
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, code)| AlertDescription::from(*code))
+        .ok_or_else(|| {
+            let known: Vec<&str> = ALERTS.iter().map(|(known, _)| *known).collect();
+            anyhow!("unknown alert {:?}, try one of {}", name, known.join(", "))
+        })
+}
+
+// one plaintext alert record: type, version, length 2, level, description
+pub fn encode(level: AlertLevel, description: AlertDescription, record_version: u16) -> [u8; 7] {
+    let [major, minor] = record_version.to_be_bytes();
+    [
+        RECORD_ALERT,
+        major,
+        minor,
+        0,
+        2,
+        level as u8,
+        u8::from(description),
+    ]
+}
+
+// the version on the client's handshake record, so the alert goes back
+// in the same one; anything that isn't an SSL3/TLS record header gets
+// the usual 0x0303
+pub fn record_version(peeked: &[u8]) -> u16 {
+    match peeked {
+        [RECORD_HANDSHAKE, 3, minor @ 0..=4, ..] => u16::from_be_bytes([3, *minor]),
+        _ => DEFAULT_RECORD_VERSION,
+    }
+}
+
+// the ClientHello is only ever peeked, so its header is still there
+pub async fn client_record_version(socket: &TcpStream) -> u16 {
+    let mut header = [0; 3];
+    match socket.peek(&mut header).await {
+        Ok(read) => record_version(&header[..read]),
+        Err(_) => DEFAULT_RECORD_VERSION,
+    }
+}
+
+// however many writes it takes
+pub async fn send<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    level: AlertLevel,
+    description: AlertDescription,
+    record_version: u16,
+) -> io::Result<()> {
+    writer
+        .write_all(&encode(level, description, record_version))
+        .await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use super::*;
+
+    // takes one byte per write, like a very full socket buffer
+    struct Trickle(Vec<u8>);
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.0.push(buf[0]);
+            Poll::Ready(Ok(1))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn encodes_an_alert_record() {
+        let record = encode(
+            AlertLevel::Fatal,
+            AlertDescription::UnrecognisedName,
+            0x0303,
+        );
+        assert_eq!(record[0], 0x15, "content type alert");
+        assert_eq!(u16::from_be_bytes([record[1], record[2]]), 0x0303);
+        assert_eq!(u16::from_be_bytes([record[3], record[4]]), 2, "length");
+        assert_eq!(record[5], 2, "fatal");
+        assert_eq!(record[6], 112, "unrecognized_name");
+    }
+
+    #[test]
+    fn encodes_named_alerts() {
+        let record = encode(
+            AlertLevel::Warning,
+            by_name("access_denied").unwrap(),
+            0x0301,
+        );
+        assert_eq!(record, [0x15, 0x03, 0x01, 0x00, 0x02, 0x01, 49]);
+        assert!(by_name("AccessDenied").is_err());
+    }
+
+    #[test]
+    fn mirrors_the_client_record_version() {
+        assert_eq!(record_version(&[0x16, 0x03, 0x01, 0x02, 0x00]), 0x0301);
+        assert_eq!(record_version(&[0x16, 0x03, 0x03]), 0x0303);
+        assert_eq!(record_version(&[0x16, 0x03, 0x00]), 0x0300);
+        // not a handshake, not a version, not enough of it
+        assert_eq!(record_version(&[0x17, 0x03, 0x01]), 0x0303);
+        assert_eq!(record_version(&[0x16, 0x7f, 0x1d]), 0x0303);
+        assert_eq!(record_version(&[0x16, 0x03]), 0x0303);
+        assert_eq!(record_version(&[]), 0x0303);
+    }
+
+    #[tokio::test]
+    async fn sends_through_partial_writes() {
+        let mut trickle = Trickle(vec![]);
+        send(
+            &mut trickle,
+            AlertLevel::Fatal,
+            AlertDescription::HandshakeFailure,
+            0x0301,
+        )
+        .await
+        .unwrap();
+        assert_eq!(trickle.0, [0x15, 0x03, 0x01, 0x00, 0x02, 0x02, 40]);
+    }
+}
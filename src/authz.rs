@@ -0,0 +1,293 @@
+// per-mapping say on which client certs get in: the tls config proves
+// the chain, this decides whether that particular cert is welcome here
+// checked after the handshake, before anything's dispatched
+
+use std::collections::HashSet;
+
+use anyhow::{Error, anyhow};
+use rustls::pki_types::CertificateDer;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::conf::{ClientAllow, MappingEntry};
+use crate::https::WebService;
+use crate::track::ConnInfo;
+
+#[derive(Debug)]
+pub struct ClientAuthz {
+    cn: HashSet<String>,
+    ou: HashSet<String>,
+    dns: HashSet<String>,
+    uri: HashSet<String>,
+    sha256: HashSet<String>,
+    // what a turned-away client gets; None closes the TLS session
+    denial: Option<WebService>,
+}
+
+fn set(list: &Option<Vec<String>>, normalize: impl Fn(&str) -> String) -> HashSet<String> {
+    list.iter().flatten().map(|item| normalize(item)).collect()
+}
+
+impl ClientAuthz {
+    // default_code: the denial when the mapping doesn't name one
+    pub fn from_mappingentry(
+        me: &MappingEntry,
+        default_code: Option<u16>,
+    ) -> Result<Option<ClientAuthz>, Error> {
+        let Some(allow) = &me.client_allow else {
+            if me.client_deny_code.is_some() || me.client_deny_body.is_some() {
+                return Err(anyhow!("client_deny_* without a client_allow to deny by"));
+            }
+            return Ok(None);
+        };
+        let ClientAllow {
+            cn,
+            ou,
+            dns,
+            uri,
+            sha256,
+        } = allow;
+        let authz = ClientAuthz {
+            cn: set(cn, str::to_string),
+            ou: set(ou, str::to_string),
+            dns: set(dns, crate::matcher::normalize),
+            uri: set(uri, str::to_string),
+            sha256: set(sha256, |hash| hash.replace(':', "").to_ascii_lowercase()),
+            denial: match me.client_deny_code.or(default_code) {
+                Some(code) => {
+                    hyper::StatusCode::from_u16(code)
+                        .map_err(|_| anyhow!("client_deny_code {} isn't an HTTP status", code))?;
+                    let body = me
+                        .client_deny_body
+                        .clone()
+                        .unwrap_or_else(|| "client certificate not allowed\n".to_string());
+                    Some(WebService::new(code, body))
+                }
+                None => None,
+            },
+        };
+        if let Some(bad) = authz
+            .sha256
+            .iter()
+            .find(|hash| hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()))
+        {
+            return Err(anyhow!(
+                "client_allow sha256 {:?} isn't a SHA-256 in hex",
+                bad
+            ));
+        }
+        Ok(Some(authz))
+    }
+
+    // Err has who was turned away, for the log
+    fn check(&self, cert: Option<&CertificateDer<'_>>) -> Result<(), String> {
+        let Some(cert) = cert else {
+            return Err("no client certificate".to_string());
+        };
+        let sha256 = Sha256::digest(cert)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        if self.sha256.contains(&sha256) {
+            return Ok(());
+        }
+        let Ok((_, parsed)) = X509Certificate::from_der(cert) else {
+            return Err(format!("unparseable certificate sha256={}", sha256));
+        };
+        let subject = parsed.subject();
+        let attribute = |attr: &x509_parser::x509::AttributeTypeAndValue<'_>| {
+            attr.as_str().ok().map(str::to_string)
+        };
+        if subject
+            .iter_common_name()
+            .filter_map(attribute)
+            .any(|cn| self.cn.contains(&cn))
+            || subject
+                .iter_organizational_unit()
+                .filter_map(attribute)
+                .any(|ou| self.ou.contains(&ou))
+        {
+            return Ok(());
+        }
+        if let Ok(Some(san)) = parsed.subject_alternative_name() {
+            for name in san.value.general_names.iter() {
+                let allowed = match name {
+                    GeneralName::DNSName(dns) => self.dns.contains(&crate::matcher::normalize(dns)),
+                    GeneralName::URI(uri) => self.uri.contains(*uri),
+                    _ => false,
+                };
+                if allowed {
+                    return Ok(());
+                }
+            }
+        }
+        Err(format!("{} sha256={}", subject, sha256))
+    }
+
+    // false: hand the stream to deny()
+    pub fn admits(&self, stream: &TlsStream<TcpStream>, conn: &ConnInfo) -> bool {
+        let cert = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first());
+        match self.check(cert) {
+            Ok(()) => true,
+            Err(who) => {
+                tracing::info!(
+                    "rule {}: turned away {} ({})",
+                    conn.rule.get().map_or("-", String::as_str),
+                    conn.client,
+                    who
+                );
+                false
+            }
+        }
+    }
+
+    pub async fn deny(&self, mut stream: TlsStream<TcpStream>) {
+        match &self.denial {
+            Some(denial) => {
+                if let Err(err) = denial.serve(stream).await {
+                    tracing::debug!("denial: {:?}", err);
+                }
+            }
+            None => {
+                let _ = stream.shutdown().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{CertificateParams, DnType, KeyPair, SanType};
+    use serde_json::json;
+
+    use super::*;
+
+    // self-signed will do: proving the chain is the tls config's job
+    fn cert(cn: &str, ou: Option<&str>, sans: Vec<SanType>) -> CertificateDer<'static> {
+        let mut params = CertificateParams::default();
+        params.distinguished_name.push(DnType::CommonName, cn);
+        if let Some(ou) = ou {
+            params
+                .distinguished_name
+                .push(DnType::OrganizationalUnitName, ou);
+        }
+        params.subject_alt_names = sans;
+        let key = KeyPair::generate().unwrap();
+        params.self_signed(&key).unwrap().der().clone()
+    }
+
+    fn dns(name: &str) -> SanType {
+        SanType::DnsName(name.try_into().unwrap())
+    }
+
+    fn uri(uri: &str) -> SanType {
+        SanType::URI(uri.try_into().unwrap())
+    }
+
+    fn authz(allow: serde_json::Value) -> Result<Option<ClientAuthz>, Error> {
+        let me: MappingEntry = serde_json::from_value(json!({
+            "exact": "mtls.example",
+            "downstreams": ["127.0.0.1:9"],
+            "client_allow": allow,
+        }))
+        .unwrap();
+        ClientAuthz::from_mappingentry(&me, None)
+    }
+
+    fn admits(allow: serde_json::Value, cert: &CertificateDer<'_>) -> bool {
+        authz(allow).unwrap().unwrap().check(Some(cert)).is_ok()
+    }
+
+    #[test]
+    fn by_subject() {
+        let alice = cert("alice", Some("ops"), vec![]);
+        assert!(admits(json!({"cn": ["alice"]}), &alice));
+        assert!(admits(json!({"ou": ["ops"]}), &alice));
+        assert!(!admits(json!({"cn": ["bob"], "ou": ["dev"]}), &alice));
+        // the OU isn't a CN, nor the other way round
+        assert!(!admits(json!({"cn": ["ops"], "ou": ["alice"]}), &alice));
+    }
+
+    #[test]
+    fn by_san_dns_normalized() {
+        let client = cert("client", None, vec![dns("Worker-1.Example.")]);
+        assert!(admits(json!({"dns": ["worker-1.example"]}), &client));
+        assert!(admits(json!({"dns": ["WORKER-1.example."]}), &client));
+        assert!(!admits(json!({"dns": ["worker-2.example"]}), &client));
+        // a CN that looks like a name isn't a SAN
+        let cn_only = cert("worker-1.example", None, vec![]);
+        assert!(!admits(json!({"dns": ["worker-1.example"]}), &cn_only));
+    }
+
+    #[test]
+    fn by_san_uri() {
+        let spiffe = "spiffe://example.org/ns/prod/sa/web";
+        let client = cert("web", None, vec![uri(spiffe), dns("web.example")]);
+        assert!(admits(json!({"uri": [spiffe]}), &client));
+        assert!(!admits(
+            json!({"uri": ["spiffe://example.org/ns/prod/sa/db"]}),
+            &client
+        ));
+        // a DNS SAN doesn't count as a URI
+        assert!(!admits(json!({"uri": ["web.example"]}), &client));
+    }
+
+    #[test]
+    fn by_sha256_however_written() {
+        let client = cert("client", None, vec![]);
+        let other = cert("client", None, vec![]);
+        let hex: String = Sha256::digest(&client)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let colons = hex
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+        let mixed: String = hex
+            .chars()
+            .enumerate()
+            .map(|(n, c)| {
+                if n % 2 == 0 {
+                    c.to_ascii_uppercase()
+                } else {
+                    c
+                }
+            })
+            .collect();
+        for written in [
+            &hex,
+            &hex.to_uppercase(),
+            &colons,
+            &colons.to_uppercase(),
+            &mixed,
+        ] {
+            assert!(admits(json!({"sha256": [written]}), &client), "{}", written);
+            assert!(!admits(json!({"sha256": [written]}), &other), "{}", written);
+        }
+        assert!(authz(json!({"sha256": [&hex[1..]]})).is_err());
+        assert!(authz(json!({"sha256": [format!("{}zz", &hex[2..])]})).is_err());
+    }
+
+    #[test]
+    fn no_cert_no_entry() {
+        let authz = authz(json!({"cn": ["alice"]})).unwrap().unwrap();
+        assert_eq!(
+            authz.check(None).unwrap_err(),
+            "no client certificate".to_string()
+        );
+        // nothing allowed, nobody admitted
+        let client = cert("alice", None, vec![]);
+        assert!(!admits(json!({}), &client));
+    }
+}
@@ -22,6 +22,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>
             .try_init()?;
     }
 
+    match &lurkr::CLI_OPTIONS.cmd {
+        Some(lurkr::Command::Check) => std::process::exit(lurkr::cmd::check()),
+        Some(lurkr::Command::EchKeygen { public_name }) => {
+            std::process::exit(lurkr::cmd::ech_keygen(public_name))
+        }
+        _ => {}
+    }
+
+    // everything gets validated up front, nothing explodes on first connection
+    if let Err(err) = lurkr::load() {
+        eprintln!("{:#}", err);
+        std::process::exit(1);
+    }
+
+    if let Some(lurkr::Command::Route { sni, ja3, ja4 }) = &lurkr::CLI_OPTIONS.cmd {
+        lurkr::cmd::route(sni, ja3.as_deref(), ja4.as_deref())?;
+        return Ok(());
+    }
+
     #[cfg(unix)]
     tokio::spawn(async move {
         use tokio::signal::unix::{SignalKind, signal};
@@ -58,6 +77,42 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>
         }
     });
 
+    lurkr::provider::spawn_providers();
+    tokio::spawn(lurkr::expiry::monitor());
+
+    if lurkr::fullcfg().admin.is_some() {
+        tokio::spawn(async move {
+            if let Err(err) = lurkr::admin::admin_listener().await {
+                tracing::error!("admin listener died: {:#}", err);
+            }
+        });
+    }
+
+    if lurkr::fullcfg()
+        .http
+        .as_ref()
+        .is_some_and(|http| http.port.is_some())
+    {
+        tokio::spawn(async move {
+            if let Err(err) = lurkr::tasks::http_listener().await {
+                tracing::error!("http listener died: {:#}", err);
+            }
+        });
+    }
+
+    for name in lurkr::fullcfg()
+        .starttls
+        .iter()
+        .flat_map(|starttls| starttls.keys())
+    {
+        let name = name.clone();
+        tokio::spawn(async move {
+            if let Err(err) = lurkr::tasks::starttls_listener(name.clone()).await {
+                tracing::error!("starttls listener {} died: {:#}", name, err);
+            }
+        });
+    }
+
     let collector_jh = tokio::spawn(lurkr::tasks::connection_collector());
     lurkr::tasks::listener().await?;
     collector_jh.await?;
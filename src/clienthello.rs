@@ -0,0 +1,398 @@
+// a ClientHello taken apart by hand, because the interesting bits
+// (ECH, fingerprints) are the ones rustls keeps to itself
+
+use std::{ops::Range, time::Duration};
+
+use anyhow::{anyhow, bail};
+use tokio::net::TcpStream;
+
+pub const EXT_SERVER_NAME: u16 = 0x0000;
+pub const EXT_SUPPORTED_GROUPS: u16 = 0x000a;
+pub const EXT_EC_POINT_FORMATS: u16 = 0x000b;
+pub const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+pub const EXT_ALPN: u16 = 0x0010;
+pub const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+pub const EXT_ECH_OUTER_EXTENSIONS: u16 = 0xfd00;
+pub const EXT_ECH: u16 = 0xfe0d;
+
+const RECORD_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const MAX_FRAGMENT: usize = 16384;
+// about a second for a ClientHello split over a few segments
+const PEEKS: usize = 40;
+const PEEK_WAIT: Duration = Duration::from_millis(25);
+
+#[derive(Debug, Clone)]
+pub struct ClientHello {
+    // the handshake message body, no handshake header
+    pub body: Vec<u8>,
+    // bytes of TLS records it came in, headers and all
+    pub records_len: usize,
+    pub record_version: u16,
+    pub legacy_version: u16,
+    pub session_id: Range<usize>,
+    pub cipher_suites: Range<usize>,
+    pub compression: Range<usize>,
+    // type and where its data sits in body, in the order sent
+    pub extensions: Vec<(u16, Range<usize>)>,
+}
+
+// just enough of a byte cursor
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pub(crate) pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+    pub(crate) fn take(&mut self, len: usize) -> anyhow::Result<Range<usize>> {
+        if self.buf.len() - self.pos < len {
+            bail!("truncated");
+        }
+        self.pos += len;
+        Ok(self.pos - len..self.pos)
+    }
+    pub(crate) fn u8(&mut self) -> anyhow::Result<u8> {
+        let at = self.take(1)?;
+        Ok(self.buf[at.start])
+    }
+    pub(crate) fn u16(&mut self) -> anyhow::Result<u16> {
+        let at = self.take(2)?;
+        Ok(u16::from_be_bytes([
+            self.buf[at.start],
+            self.buf[at.start + 1],
+        ]))
+    }
+    pub(crate) fn vec8(&mut self) -> anyhow::Result<Range<usize>> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+    pub(crate) fn vec16(&mut self) -> anyhow::Result<Range<usize>> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+    pub(crate) fn rest(&self) -> &'a [u8] {
+        &self.buf[self.pos..]
+    }
+}
+
+pub fn u16s(data: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    data.chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+}
+
+// GREASE values (RFC 8701) are 0x?a?a, and not worth remembering
+pub fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+impl ClientHello {
+    // Ok(None) means it isn't all here yet
+    pub fn parse_records(buf: &[u8]) -> anyhow::Result<Option<ClientHello>> {
+        let mut handshake = Vec::new();
+        let mut pos = 0;
+        let mut record_version = None;
+        loop {
+            if buf.len() < pos + 5 {
+                return Ok(None);
+            }
+            if buf[pos] != RECORD_HANDSHAKE {
+                bail!("not a handshake record");
+            }
+            record_version.get_or_insert(u16::from_be_bytes([buf[pos + 1], buf[pos + 2]]));
+            let len = u16::from_be_bytes([buf[pos + 3], buf[pos + 4]]) as usize;
+            if len == 0 || len > MAX_FRAGMENT {
+                bail!("bad record length {}", len);
+            }
+            if buf.len() < pos + 5 + len {
+                return Ok(None);
+            }
+            handshake.extend_from_slice(&buf[pos + 5..pos + 5 + len]);
+            pos += 5 + len;
+            if handshake.len() >= 4 {
+                if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+                    bail!("not a ClientHello");
+                }
+                let hslen = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]);
+                match (handshake.len() - 4).cmp(&(hslen as usize)) {
+                    std::cmp::Ordering::Less => continue,
+                    std::cmp::Ordering::Equal => break,
+                    std::cmp::Ordering::Greater => bail!("trailing handshake data"),
+                }
+            }
+        }
+        let mut hello = ClientHello::parse_body(handshake.split_off(4))?;
+        hello.records_len = pos;
+        hello.record_version = record_version.unwrap_or(0x0301);
+        Ok(Some(hello))
+    }
+
+    // a bare ClientHello body, nothing after the extensions
+    pub fn parse_body(body: Vec<u8>) -> anyhow::Result<ClientHello> {
+        let (hello, consumed) = ClientHello::parse_prefix(body)?;
+        if consumed != hello.body.len() {
+            bail!("trailing bytes after extensions");
+        }
+        Ok(hello)
+    }
+
+    // ECH's EncodedClientHelloInner: zeros after the extensions are fine
+    pub fn parse_padded(body: Vec<u8>) -> anyhow::Result<ClientHello> {
+        let (mut hello, consumed) = ClientHello::parse_prefix(body)?;
+        if hello.body[consumed..].iter().any(|b| *b != 0) {
+            bail!("padding isn't zeros");
+        }
+        hello.body.truncate(consumed);
+        Ok(hello)
+    }
+
+    fn parse_prefix(body: Vec<u8>) -> anyhow::Result<(ClientHello, usize)> {
+        let mut rd = Reader::new(&body);
+        let legacy_version = rd.u16()?;
+        rd.take(32)?;
+        let session_id = rd.vec8()?;
+        let cipher_suites = rd.vec16()?;
+        let compression = rd.vec8()?;
+        let mut extensions = Vec::new();
+        if !rd.rest().is_empty() {
+            let all = rd.vec16()?;
+            let mut exts = Reader::new(&body[..all.end]);
+            exts.pos = all.start;
+            while exts.pos < all.end {
+                let kind = exts.u16()?;
+                extensions.push((kind, exts.vec16()?));
+            }
+        }
+        let consumed = rd.pos;
+        Ok((
+            ClientHello {
+                body,
+                records_len: 0,
+                record_version: 0x0301,
+                legacy_version,
+                session_id,
+                cipher_suites,
+                compression,
+                extensions,
+            },
+            consumed,
+        ))
+    }
+
+    pub fn extension(&self, kind: u16) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|(ext, _)| *ext == kind)
+            .map(|(_, at)| &self.body[at.clone()])
+    }
+
+    pub fn cipher_suites(&self) -> impl Iterator<Item = u16> + '_ {
+        u16s(&self.body[self.cipher_suites.clone()])
+    }
+
+    pub fn server_name(&self) -> Option<String> {
+        // server_name_list<2>, then (type u8 = host_name, name<2>)
+        let data = self.extension(EXT_SERVER_NAME)?;
+        let mut rd = Reader::new(data);
+        let list = rd.vec16().ok()?;
+        let mut rd = Reader::new(&data[..list.end]);
+        rd.pos = list.start;
+        while rd.pos < list.end {
+            let kind = rd.u8().ok()?;
+            let name = rd.vec16().ok()?;
+            if kind == 0 {
+                return String::from_utf8(data[name].to_vec()).ok();
+            }
+        }
+        None
+    }
+
+    // ALPN protocols offered, in preference order
+    pub fn alpn(&self) -> Vec<&[u8]> {
+        let Some(data) = self.extension(EXT_ALPN) else {
+            return Vec::new();
+        };
+        let mut rd = Reader::new(data);
+        let mut protocols = Vec::new();
+        if rd.u16().is_err() {
+            return protocols;
+        }
+        while let Ok(protocol) = rd.vec8() {
+            protocols.push(&data[protocol]);
+        }
+        protocols
+    }
+
+    // back into a handshake message and TLS records, like it came in
+    pub fn to_records(&self) -> Vec<u8> {
+        let mut handshake = Vec::with_capacity(self.body.len() + 4);
+        handshake.push(HANDSHAKE_CLIENT_HELLO);
+        handshake.extend_from_slice(&(self.body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&self.body);
+        let mut records =
+            Vec::with_capacity(handshake.len() + 5 * (1 + handshake.len() / MAX_FRAGMENT));
+        for fragment in handshake.chunks(MAX_FRAGMENT) {
+            records.push(RECORD_HANDSHAKE);
+            records.extend_from_slice(&self.record_version.to_be_bytes());
+            records.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            records.extend_from_slice(fragment);
+        }
+        records
+    }
+}
+
+// peek until a whole ClientHello is in, leaving it in the socket
+pub async fn peek(socket: &TcpStream, peekbuf: &mut [u8]) -> anyhow::Result<ClientHello> {
+    for _ in 0..PEEKS {
+        let rsz = socket.peek(peekbuf).await?;
+        if rsz == 0 {
+            bail!("hung up before ClientHello");
+        }
+        if let Some(hello) = ClientHello::parse_records(&peekbuf[..rsz])? {
+            return Ok(hello);
+        }
+        if rsz == peekbuf.len() {
+            return Err(anyhow!("ClientHello bigger than {} bytes", peekbuf.len()));
+        }
+        // peek doesn't wait for more, so we do
+        tokio::time::sleep(PEEK_WAIT).await;
+    }
+    bail!("ClientHello never finished")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn push16(out: &mut Vec<u8>, data: &[u8]) {
+        out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        out.extend_from_slice(data);
+    }
+
+    pub(crate) fn server_name(name: &str) -> Vec<u8> {
+        let mut entry = vec![0];
+        push16(&mut entry, name.as_bytes());
+        let mut list = Vec::new();
+        push16(&mut list, &entry);
+        list
+    }
+
+    // a ClientHello body: TLS 1.2 legacy_version, a couple of suites,
+    // null compression, and these extensions in this order
+    pub(crate) fn body(session_id: &[u8], extensions: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0x42; 32]);
+        body.push(session_id.len() as u8);
+        body.extend_from_slice(session_id);
+        push16(&mut body, &[0x13, 0x01, 0x13, 0x02]);
+        body.extend_from_slice(&[1, 0]);
+        let mut exts = Vec::new();
+        for (kind, data) in extensions {
+            exts.extend_from_slice(&kind.to_be_bytes());
+            push16(&mut exts, data);
+        }
+        push16(&mut body, &exts);
+        body
+    }
+
+    fn record(version: u16, fragment: &[u8]) -> Vec<u8> {
+        let mut record = vec![RECORD_HANDSHAKE];
+        record.extend_from_slice(&version.to_be_bytes());
+        push16(&mut record, fragment);
+        record
+    }
+
+    fn handshake(body: &[u8]) -> Vec<u8> {
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(body);
+        handshake
+    }
+
+    #[test]
+    fn parses_one_record() {
+        let body = body(&[7; 32], &[(EXT_SERVER_NAME, &server_name("example.com"))]);
+        let records = record(0x0301, &handshake(&body));
+        let hello = ClientHello::parse_records(&records).unwrap().unwrap();
+        assert_eq!(hello.body, body);
+        assert_eq!(hello.records_len, records.len());
+        assert_eq!(hello.record_version, 0x0301);
+        assert_eq!(hello.legacy_version, 0x0303);
+        assert_eq!(&hello.body[hello.session_id.clone()], &[7; 32]);
+        assert_eq!(hello.cipher_suites().collect::<Vec<_>>(), [0x1301, 0x1302]);
+        assert_eq!(hello.server_name().as_deref(), Some("example.com"));
+        assert_eq!(hello.to_records(), records);
+    }
+
+    #[test]
+    fn reassembles_fragmented_records() {
+        let body = body(&[], &[(EXT_SERVER_NAME, &server_name("example.com"))]);
+        let handshake = handshake(&body);
+        // the handshake header split across records too
+        let mut records = Vec::new();
+        for fragment in [&handshake[..2], &handshake[2..9], &handshake[9..]] {
+            records.extend_from_slice(&record(0x0303, fragment));
+        }
+        // and whatever comes next stays out of it
+        let mut buf = records.clone();
+        buf.extend_from_slice(&[0x17, 0x03, 0x03, 0x00, 0x01, 0xff]);
+        let hello = ClientHello::parse_records(&buf).unwrap().unwrap();
+        assert_eq!(hello.body, body);
+        assert_eq!(hello.records_len, records.len());
+        assert_eq!(hello.server_name().as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn waits_for_truncated_records() {
+        let handshake = handshake(&body(&[], &[(EXT_SERVER_NAME, &server_name("a.b"))]));
+        let mut records = record(0x0301, &handshake[..20]);
+        records.extend_from_slice(&record(0x0301, &handshake[20..]));
+        for cut in 0..records.len() {
+            assert!(
+                ClientHello::parse_records(&records[..cut])
+                    .unwrap()
+                    .is_none(),
+                "{} of {} bytes",
+                cut,
+                records.len()
+            );
+        }
+        assert!(ClientHello::parse_records(&records).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_what_isnt_a_client_hello() {
+        let handshake = handshake(&body(&[], &[]));
+        let mut alert = record(0x0303, &handshake);
+        alert[0] = 0x15;
+        assert!(ClientHello::parse_records(&alert).is_err());
+        assert!(ClientHello::parse_records(&record(0x0303, &[])).is_err());
+        let mut server_hello = handshake.clone();
+        server_hello[0] = 2;
+        assert!(ClientHello::parse_records(&record(0x0303, &server_hello)).is_err());
+        let mut trailing = handshake.clone();
+        trailing.push(0);
+        assert!(ClientHello::parse_records(&record(0x0303, &trailing)).is_err());
+        // extensions claiming more than there is
+        let mut short = body(&[], &[(EXT_ALPN, &[0, 3, 2, b'h', b'2'])]);
+        short.truncate(short.len() - 1);
+        assert!(ClientHello::parse_body(short).is_err());
+    }
+
+    #[test]
+    fn padding_must_be_zeros() {
+        let body = body(&[], &[(EXT_SERVER_NAME, &server_name("inner.example"))]);
+        let mut padded = body.clone();
+        padded.extend_from_slice(&[0; 17]);
+        let hello = ClientHello::parse_padded(padded.clone()).unwrap();
+        assert_eq!(hello.body, body);
+        assert_eq!(hello.server_name().as_deref(), Some("inner.example"));
+        assert!(ClientHello::parse_body(padded.clone()).is_err());
+
+        *padded.last_mut().unwrap() = 1;
+        assert!(ClientHello::parse_padded(padded).is_err());
+        assert_eq!(ClientHello::parse_padded(body.clone()).unwrap().body, body);
+    }
+}
@@ -0,0 +1,117 @@
+// one-shot subcommands that load the config, say something, and leave
+
+use crate::dispatcher::Dispatcher;
+use crate::fingerprint::Fingerprint;
+
+// everything wrong with the config, all at once; exit code for the shell
+pub fn check() -> i32 {
+    let path = &crate::CLI_OPTIONS.conf;
+    match crate::assemble(path) {
+        Ok((assembled, diag)) => {
+            print!("{}", diag);
+            println!(
+                "{}: ok, {} mappings and {} tls configs",
+                path.display(),
+                assembled.cfg.mapping.len(),
+                assembled.tlses.len()
+            );
+            0
+        }
+        Err(diag) => {
+            print!("{}", diag);
+            println!(
+                "{}: {} errors, {} warnings",
+                path.display(),
+                diag.errors.len(),
+                diag.warnings.len()
+            );
+            1
+        }
+    }
+}
+
+// key for [ech] on stdout, DNS record value on stderr
+pub fn ech_keygen(public_name: &str) -> i32 {
+    match crate::ech::keygen(public_name) {
+        Ok((file, dns)) => {
+            print!("{}", file);
+            eprintln!("ech={}", dns);
+            0
+        }
+        Err(err) => {
+            eprintln!("couldn't make an ECH key: {:#}", err);
+            1
+        }
+    }
+}
+
+// walk MATCHLIST like a connection would, but out loud
+pub fn route(indicated: &str, ja3: Option<&str>, ja4: Option<&str>) -> anyhow::Result<()> {
+    let cfg = crate::fullcfg();
+    let matchers = crate::matchlist();
+    let indicated = crate::matcher::normalize(indicated);
+    println!(
+        "routing {:?} with {}",
+        indicated,
+        crate::CLI_OPTIONS.conf.display()
+    );
+    // no flags, no fingerprint: rules with ja3/ja4 lists get skipped
+    let fingerprint = (ja3.is_some() || ja4.is_some()).then(|| Fingerprint {
+        ja3: ja3.unwrap_or_default().to_ascii_lowercase(),
+        ja4: ja4.unwrap_or_default().to_ascii_lowercase(),
+    });
+
+    let mut winner = None;
+    for (idx, matcher) in matchers.iter().enumerate() {
+        let verdict = if winner.is_some() {
+            "not reached"
+        } else if matcher.admits(&indicated, fingerprint.as_ref()) {
+            winner = Some(matcher);
+            "MATCHED"
+        } else if matcher.matches(&indicated) {
+            "fingerprint not listed"
+        } else {
+            "no match"
+        };
+        println!(
+            "  {:>3}. {:<24} {:<40} {}",
+            idx + 1,
+            matcher.rulename(),
+            matcher.describe(),
+            verdict
+        );
+    }
+
+    let Some(matcher) = winner else {
+        println!("no rule matched, connection would be dropped");
+        return Ok(());
+    };
+    match cfg
+        .mapping
+        .get(matcher.rulename())
+        .and_then(|mapspec| mapspec.origin.as_ref())
+    {
+        Some(origin) => println!("=> rule {} from {}", matcher.rulename(), origin.display()),
+        None => println!("=> rule {}", matcher.rulename()),
+    }
+    let dispatcher = matcher.dispatcher();
+    println!("   {:?}", dispatcher);
+    if let Some(http_dispatcher) = matcher.http_dispatcher() {
+        println!("   plaintext http: {:?}", http_dispatcher);
+    }
+    let tlsname = match dispatcher {
+        Dispatcher::TLSWrappedDownstreamDispatcher { tls, .. }
+        | Dispatcher::HTTPSStaticDispatcher { tls, .. } => Some(tls),
+        _ => None,
+    };
+    if let Some(tlsname) = tlsname
+        && let Some(tlsspec) = cfg.tls.as_ref().and_then(|tlses| tlses.get(tlsname))
+    {
+        println!(
+            "   tls {}: {}",
+            tlsname,
+            serde_json::to_string_pretty(tlsspec)?.replace('\n', "\n   ")
+        );
+    }
+    Ok(())
+}
@@ -1,58 +1,493 @@
 use indexmap::IndexMap;
-use serde_derive::Deserialize;
-use std::collections::HashMap;
+use serde::Serializer;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+};
 
-#[derive(Debug, Deserialize)]
+use config::Config;
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Configuration {
     pub listener: Listener,
     // order preservation must be here otherwise the rules match in random order
     // this is a PITA while developing but also very funny
     pub mapping: IndexMap<String, MappingEntry>,
     pub tls: Option<HashMap<String, TlsConfigEntry>>,
+    // runtime inspection, off unless asked for
+    pub admin: Option<Admin>,
+    // knobs for how mappings match
+    pub matching: Option<Matching>,
+    // more mappings from elsewhere, evaluated after this file's own:
+    // every include in order, then every mapping_dirs file by name
+    // paths are relative to this file
+    pub include: Option<Vec<String>>,
+    pub mapping_dirs: Option<Vec<String>>,
+    // live mappings from somewhere else, after all of the above
+    pub provider: Option<IndexMap<String, ProviderEntry>>,
+    pub http: Option<Http>,
+    pub fallback: Option<Fallback>,
+    pub starttls: Option<IndexMap<String, StartTlsEntry>>,
+    pub ech: Option<Ech>,
+    // certificate expiry warnings, on with defaults when absent
+    pub expiry: Option<Expiry>,
+    // what TLS for a name no mapping takes gets
+    pub unmatched: Option<Unmatched>,
+    // socket options for connecting to some downstreams
+    pub downstream: Option<HashMap<String, DownstreamEntry>>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ProviderEntry {
+    // "file" watches a JSON file, "http" polls a URL for one
+    pub kind: String,
+    pub path: Option<String>,
+    // plain http:// only
+    pub url: Option<String>,
+    // how often to look, default 5 for files and 30 for http
+    pub interval_secs: Option<u64>,
 }
 
+// what an include or mapping_dirs file holds; TOML, YAML or JSON
+// route providers hand these over too, as JSON
 #[derive(Debug, Deserialize)]
+pub struct MappingFile {
+    #[serde(default)]
+    pub mapping: IndexMap<String, MappingEntry>,
+}
+
+const MAPPING_EXTENSIONS: [&str; 4] = ["toml", "yaml", "yml", "json"];
+
+impl Configuration {
+    pub fn load(path: &Path) -> anyhow::Result<Configuration> {
+        Ok(Config::builder()
+            .add_source(config::File::with_name(
+                path.to_str()
+                    .ok_or_else(|| anyhow::anyhow!("invalid pathname"))?,
+            ))
+            // LURKR_TLS__PROD__KEY_PATH=... sets tls.prod.key_path
+            .add_source(
+                config::Environment::with_prefix("LURKR")
+                    .prefix_separator("_")
+                    .separator("__"),
+            )
+            .build()?
+            .try_deserialize()?)
+    }
+
+    // pull in include and mapping_dirs files behind our own mappings
+    // a mapping name may only be defined once across all of them
+    pub fn load_includes(&mut self, path: &Path, diag: &mut Diagnostics) {
+        for mapspec in self.mapping.values_mut() {
+            mapspec.origin = Some(path.to_path_buf());
+        }
+        let base = path.parent().unwrap_or(Path::new(""));
+        let mut files: Vec<PathBuf> = self
+            .include
+            .iter()
+            .flatten()
+            .map(|include| base.join(include))
+            .collect();
+        for dir in self.mapping_dirs.iter().flatten() {
+            let dir = base.join(dir);
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    diag.error(format!("mapping_dirs {}: {}", dir.display(), err));
+                    continue;
+                }
+            };
+            let mut found: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|file| {
+                    file.is_file()
+                        && file
+                            .extension()
+                            .and_then(|ext| ext.to_str())
+                            .is_some_and(|ext| MAPPING_EXTENSIONS.contains(&ext))
+                })
+                .collect();
+            found.sort();
+            files.extend(found);
+        }
+
+        for file in files {
+            tracing::debug!("including mappings from {}", file.display());
+            let loaded: Result<MappingFile, _> = Config::builder()
+                .add_source(config::File::from(file.as_path()))
+                .build()
+                .and_then(|built| built.try_deserialize());
+            let mappings = match loaded {
+                Ok(loaded) => loaded.mapping,
+                Err(err) => {
+                    diag.error(format!("{}: {}", file.display(), err));
+                    continue;
+                }
+            };
+            for (mapname, mut mapspec) in mappings {
+                if let Some(existing) = self.mapping.get(&mapname) {
+                    diag.error(format!(
+                        "mapping {} in {} is already defined in {}",
+                        mapname,
+                        file.display(),
+                        existing
+                            .origin
+                            .as_deref()
+                            .unwrap_or(Path::new("?"))
+                            .display()
+                    ));
+                    continue;
+                }
+                mapspec.origin = Some(file.clone());
+                self.mapping.insert(mapname, mapspec);
+            }
+        }
+    }
+}
+
+// the socket options go for the http and starttls listeners too
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Listener {
     pub addr: String,
     pub port: u16,
+    // SO_REUSEPORT, for several lurkr processes on the same port
+    pub reuseport: Option<bool>,
+    // listen(2) backlog, default 1024
+    pub backlog: Option<u32>,
+    // on accepted client sockets: keepalive idle seconds, TCP_NODELAY
+    pub keepalive_secs: Option<u64>,
+    pub nodelay: Option<bool>,
+    // linux: TCP Fast Open queue length,
+    pub fastopen: Option<u32>,
+    // and binding addresses that aren't ours (tproxy, needs
+    // CAP_NET_ADMIN) or aren't up yet
+    pub transparent: Option<bool>,
+    pub freebind: Option<bool>,
 }
 
-#[derive(Debug, Deserialize)]
+// applies to connections to any of addrs, as mappings write them
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DownstreamEntry {
+    pub addrs: Vec<String>,
+    pub keepalive_secs: Option<u64>,
+    pub nodelay: Option<bool>,
+    // linux: data on the SYN, once the downstream's given us a cookie
+    pub fastopen: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Matching {
+    // regexes must match the whole name unless a mapping says otherwise
+    // off by default because '.*somewhere' configs exist in the wild
+    pub anchored_regex: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Http {
+    // plaintext on a port of its own, on the listener's addr by default
+    pub addr: Option<String>,
+    pub port: Option<u16>,
+    // and/or sniff it out on the TLS listener
+    pub same_port: Option<bool>,
+    // 308 to https:// for mappings that don't say either way
+    pub redirect: Option<bool>,
+    // where https lives from the outside, when it isn't 443
+    pub https_port: Option<u16>,
+    // ACME HTTP-01: /.well-known/acme-challenge/<token> from this directory
+    pub acme_webroot: Option<String>,
+    // or handed to something like certbot --standalone
+    pub acme_downstream: Option<String>,
+}
+
+// non-TLS on the TLS listener goes here instead of nowhere
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Fallback {
+    // "SSH-" banners
+    pub ssh: Option<Vec<String>>,
+    // startup packets, SSLRequest included
+    pub postgres: Option<Vec<String>>,
+    // plaintext HTTP, when [http] isn't taking it on this port
+    pub http: Option<Vec<String>>,
+    // clients that wait for the server to talk first, like MySQL;
+    // costs everyone a wait of server_first_timeout_ms (2000) at most
+    pub server_first: Option<Vec<String>>,
+    pub server_first_timeout_ms: Option<u64>,
+    // anything else that isn't TLS
+    pub other: Option<Vec<String>>,
+}
+
+// a listener that speaks a protocol's plaintext preamble, then routes
+// the TLS that follows by SNI like the main one
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StartTlsEntry {
+    pub protocol: StartTlsProtocol,
+    // the listener's addr by default
+    pub addr: Option<String>,
+    pub port: u16,
+    // who we say we are in greetings, "lurkr" by default
+    pub hostname: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StartTlsProtocol {
+    Smtp,
+    Imap,
+    Pop3,
+    Postgres,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Ech {
+    // an "ECHConfig file" (PRIVATE KEY plus ECHCONFIG PEM), which
+    // `lurkr ech-keygen` will make you
+    pub key_path: String,
+    // mapping for ECH aimed at our public name that we can't open, or
+    // that opens onto a mapping that terminates TLS; without one, the
+    // first lot get routed on the outer name and the second get a
+    // handshake_failure alert
+    pub fallback: Option<String>,
+}
+
+// an unrecognized_name alert when absent
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Unmatched {
+    // some other alert, e.g. "handshake_failure"
+    pub alert: Option<String>,
+    // or no alert at all
+    pub close: Option<CloseMode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CloseMode {
+    // a TCP reset
+    Rst,
+    // a plain FIN, as if nobody was home
+    Silent,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Expiry {
+    // days before notAfter to warn at, once each; default [30, 14, 7, 3, 1]
+    pub warn_days: Option<Vec<u64>>,
+    // how often to look again, default 3600
+    pub check_secs: Option<u64>,
+    // an expired cert fails the (re)load instead of just a warning
+    pub refuse_expired: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Admin {
+    // TCP, which you should keep on localhost
+    pub addr: Option<String>,
+    pub port: Option<u16>,
+    // or a unix socket, which takes precedence
+    pub unix_socket: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TlsConfigEntry {
-    // key literal or path
+    // key literal, environment variable or path
+    #[serde(serialize_with = "redacted")]
     pub key: Option<String>,
+    pub key_env: Option<String>,
     pub key_path: Option<String>,
+    // a mounted kubernetes.io/tls secret: tls.key and tls.crt in here
+    // stand in for key_path and certs_path when those aren't given
+    pub secret_dir: Option<String>,
+    // or key and certs both from a PKCS#12 bundle
+    pub pkcs12_path: Option<String>,
+    // for an ENCRYPTED PRIVATE KEY or the bundle: the name of an
+    // environment variable holding the password, or a file that does
+    pub key_password_env: Option<String>,
+    pub key_password_path: Option<String>,
     // Service (identity) side
     pub certs: Option<String>,
+    pub certs_env: Option<String>,
     pub certs_path: Option<String>,
     // Client (authproof) side
     pub require_client_auth: Option<bool>,
     pub client_certbundle: Option<String>,
+    pub client_certbundle_env: Option<String>,
     pub client_certbundle_path: Option<String>,
+    // client cert revocation: PEM literal and/or a PEM or DER file,
+    // the file picked up again when it changes
+    pub client_crl: Option<String>,
+    pub client_crl_path: Option<String>,
+    pub crl_reload_secs: Option<u64>,
+    // whole chain by default, or just the client's own cert
+    pub crl_depth: Option<CrlDepth>,
+    // a cert no CRL covers: deny by default
+    pub crl_unknown_status: Option<CrlUnknownStatus>,
+    // refuse when a CRL is past its nextUpdate
+    pub crl_enforce_expiration: Option<bool>,
+    // Handshake knobs, rustls defaults when absent
+    // "1.2" and/or "1.3"
+    pub versions: Option<Vec<String>>,
+    // rustls names, e.g. "TLS13_AES_256_GCM_SHA384", in preference order
+    pub cipher_suites: Option<Vec<String>>,
+    // e.g. "X25519MLKEM768", "X25519", "secp256r1", in preference order
+    pub kx_groups: Option<Vec<String>>,
+    // offered ALPN protocols, e.g. ["h2", "http/1.1"]
+    pub alpn: Option<Vec<String>>,
+    // Resumption: stateful cache entries, 0 turns it off
+    pub session_cache_size: Option<usize>,
+    // stateless tickets with this process's own random keys
+    pub session_tickets: Option<bool>,
+    // or keys derived from a secret file, shared between replicas
+    pub ticket_key_path: Option<String>,
+    pub ticket_rotate_secs: Option<u64>,
+    // OCSP stapling: a DER response file (re-read every refresh),
+    pub ocsp_path: Option<String>,
+    // or fetched from the responder the cert names, or this one instead
+    pub ocsp_fetch: Option<bool>,
+    pub ocsp_responder: Option<String>,
+    pub ocsp_refresh_secs: Option<u64>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CrlDepth {
+    Chain,
+    EndEntity,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CrlUnknownStatus {
+    Deny,
+    Allow,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MappingEntry {
-    // SNI matching is one of 3 handlings:
+    // SNI matching is one of 5 handlings, all case-insensitive
+    // and blind to a trailing dot:
 
     // ExactMatcher does exact string matching on "exact" field
     pub exact: Option<String>,
 
     // RegexMatcher does regex string matching on "regex" field
     pub regex: Option<String>,
+    // whole-name regex, overrides [matching] anchored_regex
+    pub anchored: Option<bool>,
+
+    // WildcardMatcher does DNS-style "*.example.com" on "wildcard" field
+    pub wildcard: Option<String>,
 
-    // Without "exact" or "regex", the matcher is universal
+    // SuffixMatcher takes "example.com" and anything under it
+    pub suffix: Option<String>,
+
+    // Without any of those, the matcher is universal
     // definitely put UniversalMatcher last in the config
 
+    // on top of the name, only for these TLS fingerprints (either list
+    // matching is enough); rules with these never shadow anything
+    pub ja3: Option<Vec<String>>,
+    pub ja4: Option<Vec<String>>,
+
     // dispatch this via TCP or wrapped-TLS conn
     pub downstreams: Option<Vec<String>>,
 
+    // raw TCP only: zero-copy via splice(2) on linux, copy elsewhere
+    pub splice: Option<bool>,
+
     // when set, terminate TLS with this config
     pub tls: Option<String>,
 
+    // or turn the TLS away: send this alert ("access_denied",
+    // "handshake_failure", any RFC 8446 name), or just hang up
+    pub alert: Option<String>,
+    pub close: Option<CloseMode>,
+
     // HTTPS response
     pub response_code: Option<u16>,
 
     // #[allow(dead_code)]
     pub response_body: Option<String>,
+
+    // with tls, only these client certs (any one entry matching will do)
+    pub client_allow: Option<ClientAllow>,
+    // what the rest get: an HTTP response (403 for response_code
+    // mappings), or for downstreams without a code, a closed session
+    pub client_deny_code: Option<u16>,
+    pub client_deny_body: Option<String>,
+
+    // plaintext HTTP, routed by Host: proxy it somewhere
+    pub http_downstreams: Option<Vec<String>>,
+    // or send it to https://, overrides [http] redirect
+    pub https_redirect: Option<bool>,
+
+    // which file this came from, for telling people where to look
+    #[serde(skip_deserializing)]
+    pub origin: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ClientAllow {
+    // subject common names and organizational units
+    pub cn: Option<Vec<String>>,
+    pub ou: Option<Vec<String>>,
+    // subjectAltName entries: DNS names, URIs (SPIFFE IDs and such)
+    pub dns: Option<Vec<String>>,
+    pub uri: Option<Vec<String>>,
+    // hex SHA-256 of the whole DER certificate, colons optional
+    pub sha256: Option<Vec<String>>,
+}
+
+impl MappingEntry {
+    // "name (file)" for diagnostics
+    pub fn whence(&self, mapname: &str) -> String {
+        match &self.origin {
+            Some(origin) => format!("{} ({})", mapname, origin.display()),
+            None => mapname.to_string(),
+        }
+    }
+}
+
+// everything wrong with a configuration, so it can all get fixed in one go
+// errors refuse to load; warnings load but probably aren't what you meant
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Diagnostics {
+    pub fn error(&mut self, msg: String) {
+        self.errors.push(msg);
+    }
+
+    pub fn warn(&mut self, msg: String) {
+        self.warnings.push(msg);
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for err in self.errors.iter() {
+            writeln!(f, "error: {}", err)?;
+        }
+        for warning in self.warnings.iter() {
+            writeln!(f, "warning: {}", warning)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+// secrets don't leave the building, even over the admin API
+fn redacted<S: Serializer>(secret: &Option<String>, ser: S) -> Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => ser.serialize_some("<redacted>"),
+        None => ser.serialize_none(),
+    }
 }
@@ -1,68 +1,88 @@
 use crate::dispatcher::Dispatcher;
-use rustls::server::Acceptor;
+use crate::fingerprint::Fingerprint;
+use crate::sniff::Sniffed;
+use crate::track::Tracked;
+use std::net::SocketAddr;
 use tokio::net::TcpStream;
 
 const PEEK_SIZE: usize = 10240;
 
-pub async fn handle_connection(socket: TcpStream) {
+pub async fn handle_connection(socket: TcpStream, client: SocketAddr) {
+    let conn = Tracked::new(client);
     let mut peekbuf = [0; PEEK_SIZE];
     // "peek" into the socket to retrieve TLS
-    // ClientHello and SNI
-    let rsz = socket
-        .peek(&mut peekbuf)
+    // ClientHello and SNI, or whatever else it is
+    let cfg = crate::fullcfg();
+    let (sniffed, _) = crate::sniff::sniff(&socket, &mut peekbuf, cfg.fallback.as_ref())
         .await
         .expect("couldn't peek from socket");
-    // EOF case
-    if rsz == 0 {
-        return;
-    }
-
-    // handle the confused-case where they plaintext HTTP'ed at us
-    if peekbuf
-        .windows(4)
-        .any(move |subslice| subslice == "HTTP".as_bytes())
-    {
-        tracing::debug!("HTTP connection detected, this only supports TLS");
-        return;
+    match sniffed {
+        Sniffed::Tls => {}
+        // EOF case
+        Sniffed::Eof => return,
+        // plaintext HTTP'ed at us: routed by Host if we're into that,
+        // otherwise it's the confused-case
+        Sniffed::Http
+            if cfg
+                .http
+                .as_ref()
+                .is_some_and(|http| http.same_port == Some(true)) =>
+        {
+            return crate::http::dispatch(socket, &conn).await;
+        }
+        _ => return crate::sniff::fallback(sniffed, socket, &conn).await,
     }
+    drop(cfg);
 
-    let mut tls_ponder = Acceptor::default();
-    tls_ponder
-        .read_tls(&mut &peekbuf[..])
-        .expect("couldn't read data from connection");
-    match tls_ponder.accept() {
-        Ok(None) => {
-            // TODO: refactor so it peeks a few times
-            tracing::debug!("haven't consumed a ClientHello");
+    // the whole ClientHello, however many segments that takes
+    let hello = match crate::clienthello::peek(&socket, &mut peekbuf).await {
+        Ok(hello) => hello,
+        Err(err) => {
+            tracing::debug!("haven't got a ClientHello: {:#}", err);
             return;
         }
-        Ok(Some(accepted)) => {
-            let ch = accepted.client_hello();
-            match ch.server_name() {
-                None => {
-                    // Didn't get SNI, send to first universal match
-                    tracing::debug!("no name indicated");
-                    if let Some(dispatcher) = Dispatcher::from_indicated("") {
-                        dispatcher.do_dispatch(socket).await
-                    } else {
-                        tracing::warn!("no dispatcher for zero-string: elvis left the building");
-                        panic!("zero-string dispatcher missing");
-                    }
-                }
-                Some(sn) => {
-                    tracing::debug!("indicated: {:?}", sn);
-                    if let Some(dispatcher) = Dispatcher::from_indicated(sn) {
-                        dispatcher.do_dispatch(socket).await
-                    } else {
-                        // should be unreachable
-                        panic!("no dispatcher for indicated");
-                    }
-                }
+    };
+    let fingerprint = Fingerprint::of(&hello);
+    tracing::debug!("ja3 {} ja4 {}", fingerprint.ja3, fingerprint.ja4);
+    conn.fingerprint.set(fingerprint.clone()).ok();
+
+    // ECH for us gets routed on the inner name, and that's that
+    let ech = crate::ECH.read().unwrap().clone();
+    let socket = match ech {
+        Some(keys) => {
+            match crate::ech::dispatch(socket, &hello, &fingerprint, &keys, &conn).await {
+                Some(socket) => socket,
+                None => return,
             }
         }
-        Err((e, alert)) => {
-            tracing::debug!("err: {:?} alert: {:?}", e, alert);
-            return;
+        None => socket,
+    };
+
+    match hello.server_name() {
+        None => {
+            // Didn't get SNI, send to first universal match
+            tracing::debug!("no name indicated");
+            if let Some((rulename, dispatcher)) = Dispatcher::from_indicated("", Some(&fingerprint))
+            {
+                conn.rule.set(rulename).ok();
+                dispatcher.do_dispatch(socket, &conn).await
+            } else {
+                tracing::warn!("no dispatcher for zero-string: elvis left the building");
+                panic!("zero-string dispatcher missing");
+            }
+        }
+        Some(sn) => {
+            tracing::debug!("indicated: {:?}", sn);
+            conn.sni.set(sn.clone()).ok();
+            if let Some((rulename, dispatcher)) =
+                Dispatcher::from_indicated(&sn, Some(&fingerprint))
+            {
+                conn.rule.set(rulename).ok();
+                dispatcher.do_dispatch(socket, &conn).await
+            } else {
+                // should be unreachable
+                panic!("no dispatcher for indicated");
+            }
         }
     }
 }
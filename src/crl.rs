@@ -0,0 +1,270 @@
+// revocation lists for client certs, and a verifier that picks up a
+// rewritten client_crl_path without a reload
+
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Error, anyhow};
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::pki_types::{CertificateDer, CertificateRevocationListDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{DigitallySignedStruct, DistinguishedName, RootCertStore, SignatureScheme};
+use rustls_pki_types::pem::PemObject;
+
+use crate::conf::TlsConfigEntry;
+
+pub const DEFAULT_RELOAD_SECS: u64 = 60;
+
+pub fn wanted(tlsspec: &TlsConfigEntry) -> bool {
+    tlsspec.client_crl.is_some() || tlsspec.client_crl_path.is_some()
+}
+
+// inline ones first, then the file's: PEM, or a bare DER CRL
+pub fn load(tlsspec: &TlsConfigEntry) -> Result<Vec<CertificateRevocationListDer<'static>>, Error> {
+    let mut crls = vec![];
+    if let Some(literal) = &tlsspec.client_crl {
+        for crl in CertificateRevocationListDer::pem_slice_iter(literal.as_bytes()) {
+            crls.push(crl.map_err(|err| anyhow!("bad inline client_crl: {}", err))?);
+        }
+    }
+    if let Some(path) = &tlsspec.client_crl_path {
+        let contents =
+            std::fs::read(path).map_err(|err| anyhow!("couldn't read {}: {}", path, err))?;
+        if contents.trim_ascii_start().starts_with(b"-----BEGIN") {
+            for crl in CertificateRevocationListDer::pem_slice_iter(&contents) {
+                crls.push(crl.map_err(|err| anyhow!("bad CRL in {}: {}", path, err))?);
+            }
+        } else {
+            crls.push(CertificateRevocationListDer::from(contents));
+        }
+    }
+    if crls.is_empty() {
+        return Err(anyhow!("client CRLs configured but none found"));
+    }
+    Ok(crls)
+}
+
+// same roots and settings, whatever the CRL file says now
+#[derive(Debug)]
+pub struct ReloadingVerifier {
+    current: RwLock<Arc<dyn ClientCertVerifier>>,
+    // roots don't change under us, so the hints don't either
+    hints: Vec<DistinguishedName>,
+}
+
+impl ReloadingVerifier {
+    pub fn new(verifier: Arc<dyn ClientCertVerifier>) -> ReloadingVerifier {
+        ReloadingVerifier {
+            hints: verifier.root_hint_subjects().to_vec(),
+            current: RwLock::new(verifier),
+        }
+    }
+
+    fn current(&self) -> Arc<dyn ClientCertVerifier> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+impl ClientCertVerifier for ReloadingVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.current().offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.current().client_auth_mandatory()
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &self.hints
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.current()
+            .verify_client_cert(end_entity, intermediates, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.current().verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.current().verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.current().supported_verify_schemes()
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+// polls client_crl_path's mtime until the acceptor goes away
+pub async fn keep_current(
+    verifier: Weak<ReloadingVerifier>,
+    tlsspec: TlsConfigEntry,
+    roots: Arc<RootCertStore>,
+) {
+    let Some(path) = tlsspec.client_crl_path.clone() else {
+        return;
+    };
+    let every = Duration::from_secs(
+        tlsspec
+            .crl_reload_secs
+            .unwrap_or(DEFAULT_RELOAD_SECS)
+            .max(1),
+    );
+    let mut seen = modified(&path);
+    loop {
+        tokio::time::sleep(every).await;
+        let Some(verifier) = verifier.upgrade() else {
+            return;
+        };
+        verifier.refresh(&tlsspec, &roots, &path, &mut seen);
+    }
+}
+
+impl ReloadingVerifier {
+    // one look at the file; a CRL file that doesn't load leaves the last
+    // good one in charge
+    fn refresh(
+        &self,
+        tlsspec: &TlsConfigEntry,
+        roots: &Arc<RootCertStore>,
+        path: &str,
+        seen: &mut Option<SystemTime>,
+    ) {
+        let now = modified(path);
+        if now == *seen {
+            return;
+        }
+        *seen = now;
+        match load(tlsspec)
+            .and_then(|crls| crate::tls::client_verifier(tlsspec, roots.clone(), crls))
+        {
+            Ok(fresh) => {
+                log::info!("reloaded client CRLs from {}", path);
+                *self.current.write().unwrap() = fresh;
+            }
+            Err(err) => log::warn!("keeping the old client CRLs: {:#}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    // made by testdata/crl/generate.py; checked with openssl verify -crl_check
+    const CA: &[u8] = include_bytes!("../testdata/crl/ca.der");
+    const REVOKED_CLIENT: &[u8] = include_bytes!("../testdata/crl/revoked-client.der");
+    const GOOD_CLIENT: &[u8] = include_bytes!("../testdata/crl/good-client.der");
+    const REVOKING: &[u8] = include_bytes!("../testdata/crl/revoking.pem");
+    const EMPTY: &[u8] = include_bytes!("../testdata/crl/empty.pem");
+
+    fn roots() -> Arc<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from(CA)).unwrap();
+        Arc::new(roots)
+    }
+
+    fn tlsspec(crl_path: &Path) -> TlsConfigEntry {
+        serde_json::from_value(serde_json::json!({
+            "require_client_auth": true,
+            "client_crl_path": crl_path,
+        }))
+        .unwrap()
+    }
+
+    // a CRL file with an mtime that's sure to differ from the last
+    fn rewrite(path: &Path, contents: &[u8], mtime: u64) {
+        std::fs::write(path, contents).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+            .unwrap();
+    }
+
+    fn admits(verifier: &dyn ClientCertVerifier, client: &[u8]) -> bool {
+        // well inside the fixtures' validity
+        let now = UnixTime::since_unix_epoch(Duration::from_secs(1_800_000_000));
+        verifier
+            .verify_client_cert(&CertificateDer::from(client), &[], now)
+            .is_ok()
+    }
+
+    #[test]
+    fn rejects_revoked_client_certs() {
+        let spec = tlsspec(Path::new("unused"));
+        let crls = CertificateRevocationListDer::pem_slice_iter(REVOKING)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let verifier = crate::tls::client_verifier(&spec, roots(), crls).unwrap();
+        assert!(!admits(verifier.as_ref(), REVOKED_CLIENT));
+        assert!(admits(verifier.as_ref(), GOOD_CLIENT));
+    }
+
+    #[test]
+    fn follows_the_crl_file() {
+        let dir = std::env::temp_dir().join(format!("lurkr-crl-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("client.crl");
+        rewrite(&path, EMPTY, 1);
+        let spec = tlsspec(&path);
+        let verifier = ReloadingVerifier::new(
+            crate::tls::client_verifier(&spec, roots(), load(&spec).unwrap()).unwrap(),
+        );
+        let path = path.to_str().unwrap();
+        let mut seen = modified(path);
+        assert!(admits(&verifier, REVOKED_CLIENT));
+
+        // same mtime, not even looked at
+        rewrite(path.as_ref(), REVOKING, 1);
+        verifier.refresh(&spec, &roots(), path, &mut seen);
+        assert!(admits(&verifier, REVOKED_CLIENT));
+
+        rewrite(path.as_ref(), REVOKING, 2);
+        verifier.refresh(&spec, &roots(), path, &mut seen);
+        assert!(!admits(&verifier, REVOKED_CLIENT));
+        assert!(admits(&verifier, GOOD_CLIENT));
+
+        // garbage, then a CRL that isn't DER at all: the last good stays
+        for (mtime, bad) in [
+            (3, &b"-----BEGIN X509 CRL-----\n!!\n"[..]),
+            (4, b"not a crl"),
+        ] {
+            rewrite(path.as_ref(), bad, mtime);
+            verifier.refresh(&spec, &roots(), path, &mut seen);
+            assert!(!admits(&verifier, REVOKED_CLIENT), "{}", mtime);
+            assert!(admits(&verifier, GOOD_CLIENT), "{}", mtime);
+        }
+
+        rewrite(path.as_ref(), EMPTY, 5);
+        verifier.refresh(&spec, &roots(), path, &mut seen);
+        assert!(admits(&verifier, REVOKED_CLIENT));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
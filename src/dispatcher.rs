@@ -1,9 +1,7 @@
+use anyhow::anyhow;
 use rand::seq::IndexedRandom;
 use rustls::AlertDescription;
-use rustls::internal::msgs::{
-    enums::AlertLevel,
-    message::{Message, PlainMessage},
-};
+use serde::{Serialize, Serializer, ser::SerializeStruct};
 use std::{fmt::Formatter, sync::Arc};
 use tokio::{
     io::{self, AsyncWriteExt},
@@ -11,13 +9,129 @@ use tokio::{
 };
 use tokio_rustls::TlsAcceptor;
 
+use crate::TlsMap;
+use crate::alert::{self, AlertLevel};
+use crate::authz::ClientAuthz;
+use crate::conf::{CloseMode, Configuration, MappingEntry};
+use crate::fingerprint::Fingerprint;
 use crate::https::WebService;
-use crate::{conf::MappingEntry, matcher::Matcher};
+use crate::track::ConnInfo;
 
 impl std::fmt::Debug for Dispatcher {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        // TODO: make this not shit
-        f.debug_struct("Dispatcher").finish()
+        match self {
+            Dispatcher::TCPDownstreamDispatcher {
+                downstreams,
+                splice,
+            } => f
+                .debug_struct("TCPDownstreamDispatcher")
+                .field("downstreams", downstreams)
+                .field("splice", splice)
+                .finish(),
+            Dispatcher::TLSWrappedDownstreamDispatcher {
+                downstreams,
+                tls,
+                authz,
+                ..
+            } => f
+                .debug_struct("TLSWrappedDownstreamDispatcher")
+                .field("downstreams", downstreams)
+                .field("tls", tls)
+                .field("client_allow", &authz.is_some())
+                .finish(),
+            Dispatcher::HTTPSStaticDispatcher {
+                webservice,
+                tls,
+                authz,
+                ..
+            } => f
+                .debug_struct("HTTPSStaticDispatcher")
+                .field("response_code", &webservice.response_code())
+                .field("tls", tls)
+                .field("client_allow", &authz.is_some())
+                .finish(),
+            Dispatcher::HTTPSRedirectDispatcher { https_port } => f
+                .debug_struct("HTTPSRedirectDispatcher")
+                .field("https_port", https_port)
+                .finish(),
+            Dispatcher::TLSAlertDispatcher {
+                alert_level,
+                alert_description,
+            } => f
+                .debug_struct("TLSAlertDispatcher")
+                .field("alert_level", alert_level)
+                .field("alert_description", alert_description)
+                .finish(),
+            Dispatcher::CloseDispatcher { rst } => {
+                f.debug_struct("CloseDispatcher").field("rst", rst).finish()
+            }
+        }
+    }
+}
+
+// for the admin API; acceptors aren't data so they go by TLS config name
+impl Serialize for Dispatcher {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Dispatcher::TCPDownstreamDispatcher {
+                downstreams,
+                splice,
+            } => {
+                let mut st = ser.serialize_struct("Dispatcher", 3)?;
+                st.serialize_field("kind", "TCPDownstreamDispatcher")?;
+                st.serialize_field("downstreams", downstreams)?;
+                st.serialize_field("splice", splice)?;
+                st.end()
+            }
+            Dispatcher::TLSWrappedDownstreamDispatcher {
+                downstreams,
+                tls,
+                authz,
+                ..
+            } => {
+                let mut st = ser.serialize_struct("Dispatcher", 4)?;
+                st.serialize_field("kind", "TLSWrappedDownstreamDispatcher")?;
+                st.serialize_field("downstreams", downstreams)?;
+                st.serialize_field("tls", tls)?;
+                st.serialize_field("client_allow", &authz.is_some())?;
+                st.end()
+            }
+            Dispatcher::HTTPSStaticDispatcher {
+                webservice,
+                tls,
+                authz,
+                ..
+            } => {
+                let mut st = ser.serialize_struct("Dispatcher", 4)?;
+                st.serialize_field("kind", "HTTPSStaticDispatcher")?;
+                st.serialize_field("response_code", &webservice.response_code())?;
+                st.serialize_field("tls", tls)?;
+                st.serialize_field("client_allow", &authz.is_some())?;
+                st.end()
+            }
+            Dispatcher::HTTPSRedirectDispatcher { https_port } => {
+                let mut st = ser.serialize_struct("Dispatcher", 2)?;
+                st.serialize_field("kind", "HTTPSRedirectDispatcher")?;
+                st.serialize_field("https_port", https_port)?;
+                st.end()
+            }
+            Dispatcher::TLSAlertDispatcher {
+                alert_level,
+                alert_description,
+            } => {
+                let mut st = ser.serialize_struct("Dispatcher", 3)?;
+                st.serialize_field("kind", "TLSAlertDispatcher")?;
+                st.serialize_field("alert_level", &format!("{:?}", alert_level))?;
+                st.serialize_field("alert_description", &format!("{:?}", alert_description))?;
+                st.end()
+            }
+            Dispatcher::CloseDispatcher { rst } => {
+                let mut st = ser.serialize_struct("Dispatcher", 2)?;
+                st.serialize_field("kind", "CloseDispatcher")?;
+                st.serialize_field("rst", rst)?;
+                st.end()
+            }
+        }
     }
 }
 
@@ -26,26 +140,33 @@ pub enum Dispatcher {
     // represent raw TCP
     TCPDownstreamDispatcher {
         downstreams: Vec<String>,
+        // kernel-side passthrough, we never look at the payload anyway
+        splice: bool,
     },
     // represent a plaintext connection, like stunnel
     TLSWrappedDownstreamDispatcher {
         downstreams: Vec<String>,
         // reference to a tls acceptor for upstream term
         acceptor: Arc<TlsAcceptor>,
+        // and the name it goes by in the config
+        tls: String,
+        // which client certs this mapping lets through
+        authz: Option<Arc<ClientAuthz>>,
     },
 
     // sends the client one 404 or whatever
     HTTPSStaticDispatcher {
         webservice: WebService,
         acceptor: Arc<TlsAcceptor>,
+        tls: String,
+        authz: Option<Arc<ClientAuthz>>,
     },
 
-    // NOT IMPLEMENTED
-    // should be HTTP 308 with Location: <place>
+    // plaintext HTTP only: 308 with Location: https://<host><path>
     // content-length 0
-    // HTTPSRedirectDispatcher {
-    //     //
-    // },
+    HTTPSRedirectDispatcher {
+        https_port: Option<u16>,
+    },
 
     // you don't want no part of this shit
     // so send them a TLS "PC LOAD LETTER"
@@ -53,21 +174,34 @@ pub enum Dispatcher {
         alert_level: AlertLevel,
         alert_description: AlertDescription,
     },
-    // effectively does nothing
-    // when the socket goes out of scope, RST
-    // not even in use lmao
-    // NothingDispatcher,
+    // no alert, just hang up: RST, or a FIN with nothing before it
+    CloseDispatcher {
+        rst: bool,
+    },
 }
 
 impl Dispatcher {
-    pub async fn do_dispatch(&self, mut clientsock: TcpStream) {
+    pub fn downstreams(&self) -> &[String] {
         match self {
-            Dispatcher::TCPDownstreamDispatcher { downstreams } => {
-                let chosen = downstreams
-                    .choose(&mut rand::rng())
-                    .expect("no downstreams in dispatcher");
+            Dispatcher::TCPDownstreamDispatcher { downstreams, .. }
+            | Dispatcher::TLSWrappedDownstreamDispatcher { downstreams, .. } => downstreams,
+            _ => &[],
+        }
+    }
+
+    pub async fn do_dispatch(&self, mut clientsock: TcpStream, conn: &ConnInfo) {
+        match self {
+            Dispatcher::TCPDownstreamDispatcher {
+                downstreams,
+                splice,
+            } => {
+                let Some(chosen) = downstreams.choose(&mut rand::rng()) else {
+                    tracing::error!("no downstreams in dispatcher");
+                    return;
+                };
                 tracing::debug!("connect ye to {}", chosen);
-                match crate::proxy::tcp_proxy_addr(clientsock, chosen).await {
+                conn.downstream.set(chosen.clone()).ok();
+                match crate::proxy::tcp_proxy_addr(clientsock, chosen, *splice, conn).await {
                     io::Result::Ok(_) => {
                         tracing::debug!("normal termination");
                     }
@@ -91,31 +225,61 @@ impl Dispatcher {
                 alert_description,
             } => {
                 tracing::debug!("sending TLS alert & closing stream");
-                // TODO: has to be a better modern way to alert
-                clientsock
-                    .write(
-                        &PlainMessage::try_from(Message::build_alert(
-                            *alert_level,
-                            *alert_description,
-                        ))
-                        .expect("couldn't convert alert to PlainMessage")
-                        .into_unencrypted_opaque()
-                        .encode(),
-                    )
-                    .await
-                    .expect("Error sending TLS alert");
+                let record_version = alert::client_record_version(&clientsock).await;
+                if let Err(err) = alert::send(
+                    &mut clientsock,
+                    *alert_level,
+                    *alert_description,
+                    record_version,
+                )
+                .await
+                {
+                    tracing::debug!("couldn't send TLS alert: {:?}", err);
+                }
                 // FIN here, otherwise the socket will RST
                 let _ = clientsock.shutdown().await;
             }
+            Dispatcher::CloseDispatcher { rst } => {
+                if *rst {
+                    tracing::debug!("resetting connection");
+                    // zero linger makes the close a RST
+                    let _ = clientsock.set_zero_linger();
+                } else {
+                    tracing::debug!("closing stream without a word");
+                    // the ClientHello was only peeked, and closing over
+                    // unread bytes is a RST as well
+                    let mut scratch = [0; 4096];
+                    while matches!(clientsock.try_read(&mut scratch), Ok(read) if read > 0) {}
+                    let _ = clientsock.shutdown().await;
+                }
+            }
+            Dispatcher::HTTPSRedirectDispatcher { .. } => {
+                tracing::debug!("to serve_plain");
+                if let Err(err) = crate::http::serve_plain(clientsock).await {
+                    tracing::debug!("plaintext termination: {:?}", err);
+                }
+            }
             Dispatcher::TLSWrappedDownstreamDispatcher {
                 downstreams,
                 acceptor,
+                authz,
+                ..
             } => {
-                let chosen = downstreams
-                    .choose(&mut rand::rng())
-                    .expect("no downstreams in dispatcher");
+                let Some(chosen) = downstreams.choose(&mut rand::rng()) else {
+                    tracing::error!("no downstreams in dispatcher");
+                    return;
+                };
                 tracing::debug!("TLS-term and connect to {}", chosen);
-                match crate::proxy::tls_proxy_addr(clientsock, chosen, acceptor.clone()).await {
+                conn.downstream.set(chosen.clone()).ok();
+                match crate::proxy::tls_proxy_addr(
+                    clientsock,
+                    chosen,
+                    acceptor.clone(),
+                    authz.as_deref(),
+                    conn,
+                )
+                .await
+                {
                     io::Result::Ok(_) => {
                         tracing::debug!("normal termination");
                     }
@@ -136,10 +300,12 @@ impl Dispatcher {
             Dispatcher::HTTPSStaticDispatcher {
                 webservice,
                 acceptor,
+                authz,
+                ..
             } => {
                 tracing::debug!("to https_serve_conn");
                 match webservice
-                    .https_serve_conn(clientsock, acceptor.clone())
+                    .https_serve_conn(clientsock, acceptor.clone(), authz.as_deref(), conn)
                     .await
                 {
                     io::Result::Ok(_) => {
@@ -161,76 +327,166 @@ impl Dispatcher {
             }
         }
     }
+    // an alert or a hang-up instead of a handshake, None when neither is asked for
+    pub fn rejection(
+        alert: Option<&str>,
+        close: Option<CloseMode>,
+    ) -> anyhow::Result<Option<Dispatcher>> {
+        match (alert, close) {
+            (Some(_), Some(_)) => Err(anyhow!("alert or close, not both")),
+            (Some(alert), None) => Ok(Some(Dispatcher::TLSAlertDispatcher {
+                alert_level: AlertLevel::Fatal,
+                alert_description: alert::by_name(alert)?,
+            })),
+            (None, Some(close)) => Ok(Some(Dispatcher::CloseDispatcher {
+                rst: close == CloseMode::Rst,
+            })),
+            (None, None) => Ok(None),
+        }
+    }
+    // what [unmatched] says, unrecognized_name when it doesn't
+    pub fn unmatched(cfg: &Configuration) -> anyhow::Result<Dispatcher> {
+        let unmatched = cfg.unmatched.as_ref();
+        Ok(Dispatcher::rejection(
+            unmatched.and_then(|unmatched| unmatched.alert.as_deref()),
+            unmatched.and_then(|unmatched| unmatched.close),
+        )?
+        .unwrap_or(Dispatcher::TLSAlertDispatcher {
+            alert_level: AlertLevel::Fatal,
+            // it's a "z" in the standard #gotem
+            alert_description: AlertDescription::UnrecognisedName,
+        }))
+    }
     // Dispatchers determine how to execute
-    pub fn from_mappingentry(me: &MappingEntry) -> Option<Dispatcher> {
+    pub fn from_mappingentry(me: &MappingEntry, tlsmap: &TlsMap) -> anyhow::Result<Dispatcher> {
+        // nothing to pick from is a config mistake, not a connection-time panic
+        if me
+            .downstreams
+            .as_ref()
+            .is_some_and(|downstreams| downstreams.is_empty())
+        {
+            return Err(anyhow!("downstreams is empty"));
+        }
+        if let Some(rejection) = Dispatcher::rejection(me.alert.as_deref(), me.close)? {
+            if me.tls.is_some() || me.downstreams.is_some() || me.response_code.is_some() {
+                return Err(anyhow!(
+                    "alert and close turn TLS away, they don't go with tls, downstreams or response_code"
+                ));
+            }
+            return Ok(rejection);
+        }
         if let Some(tlsname) = &me.tls {
-            if let Some(acceptor) = crate::TLSMAP.get(tlsname) {
-                if let Some(downstreams) = &me.downstreams {
-                    tracing::debug!("TLSWrappedDownstreamDispatcher");
-                    return Some(Dispatcher::TLSWrappedDownstreamDispatcher {
-                        downstreams: downstreams.clone(),
-                        acceptor: acceptor.clone(),
-                    });
-                }
-                if let Some(response_code) = me.response_code {
-                    tracing::debug!("HTTPSStaticDispatcher");
-                    if let Some(response_body) = &me.response_body {
-                        return Some(Dispatcher::HTTPSStaticDispatcher {
-                            webservice: WebService::new(response_code, response_body.clone()),
-                            acceptor: acceptor.clone(),
-                        });
-                    }
-                }
-            } else {
+            let Some(acceptor) = tlsmap.get(tlsname) else {
                 tracing::debug!("not found tls acceptor");
-                panic!("named tls config not found");
-            }
-        } else {
+                return Err(anyhow!("named tls config {} not found", tlsname));
+            };
             if let Some(downstreams) = &me.downstreams {
-                tracing::debug!("TCPDownstreamDispatcher");
-                return Some(Dispatcher::TCPDownstreamDispatcher {
+                tracing::debug!("TLSWrappedDownstreamDispatcher");
+                return Ok(Dispatcher::TLSWrappedDownstreamDispatcher {
                     downstreams: downstreams.clone(),
+                    acceptor: acceptor.clone(),
+                    tls: tlsname.clone(),
+                    // the downstream's protocol is anyone's guess: just close
+                    authz: ClientAuthz::from_mappingentry(me, None)?.map(Arc::new),
                 });
             }
+            if let Some(response_code) = me.response_code {
+                tracing::debug!("HTTPSStaticDispatcher");
+                if let Some(response_body) = &me.response_body {
+                    return Ok(Dispatcher::HTTPSStaticDispatcher {
+                        webservice: WebService::new(response_code, response_body.clone()),
+                        acceptor: acceptor.clone(),
+                        tls: tlsname.clone(),
+                        authz: ClientAuthz::from_mappingentry(me, Some(403))?.map(Arc::new),
+                    });
+                }
+            }
+        } else if let Some(downstreams) = &me.downstreams {
+            tracing::debug!("TCPDownstreamDispatcher");
+            return Ok(Dispatcher::TCPDownstreamDispatcher {
+                downstreams: downstreams.clone(),
+                splice: me.splice == Some(true),
+            });
+        } else if me.response_code.is_none() && me.http_downstreams.is_some() {
+            // plaintext-only mapping, TLS for this name gets the cold shoulder
+            return Ok(Dispatcher::TLSAlertDispatcher {
+                alert_level: AlertLevel::Fatal,
+                alert_description: AlertDescription::UnrecognisedName,
+            });
         }
-        None
+        Err(anyhow!(
+            "not dispatchable: needs downstreams, tls with response_code and response_body, alert or close"
+        ))
     }
-    pub fn from_indicated(indicated: &str) -> Option<Dispatcher> {
-        for matcher in crate::MATCHLIST.iter() {
-            match matcher {
-                Matcher::ExactMatcher {
-                    rulename,
-                    exact,
-                    dispatcher,
-                } => {
-                    if exact.as_str() == indicated {
-                        tracing::debug!("rule {} matched exact: {}", rulename, indicated);
-                        return Some(dispatcher.clone());
-                    } else {
-                        tracing::debug!("rule {} no matched exact: {}", rulename, indicated);
-                    }
-                }
-                Matcher::RegexMatcher {
-                    rulename,
-                    regex,
-                    dispatcher,
-                } => {
-                    if regex.is_match(indicated) {
-                        tracing::debug!("rule {} regexed: {}", rulename, indicated);
-                        return Some(dispatcher.clone());
-                    } else {
-                        tracing::debug!("rule {} no matched regex: {}", rulename, indicated);
-                    }
-                }
-                Matcher::UniversalMatcher {
-                    rulename,
-                    dispatcher,
-                } => {
-                    tracing::debug!("rule {} universal match", rulename);
-                    return Some(dispatcher.clone());
-                }
+    // the plaintext-HTTP side of a mapping, None means 404
+    pub fn http_from_mappingentry(
+        me: &MappingEntry,
+        cfg: &Configuration,
+    ) -> anyhow::Result<Option<Dispatcher>> {
+        let http = cfg.http.as_ref();
+        if let Some(downstreams) = &me.http_downstreams {
+            if downstreams.is_empty() {
+                return Err(anyhow!("http_downstreams is empty"));
+            }
+            if me.https_redirect == Some(true) {
+                return Err(anyhow!("http_downstreams or https_redirect, not both"));
             }
+            return Ok(Some(Dispatcher::TCPDownstreamDispatcher {
+                downstreams: downstreams.clone(),
+                splice: me.splice == Some(true),
+            }));
         }
-        None
+        let redirect = me
+            .https_redirect
+            .or_else(|| http.and_then(|http| http.redirect))
+            .unwrap_or(false);
+        Ok(redirect.then(|| Dispatcher::HTTPSRedirectDispatcher {
+            https_port: http.and_then(|http| http.https_port),
+        }))
+    }
+    // the winning rule's name comes along for the ride
+    pub fn from_indicated(
+        indicated: &str,
+        fingerprint: Option<&Fingerprint>,
+    ) -> Option<(String, Dispatcher)> {
+        let indicated = crate::matcher::normalize(indicated);
+        let matchers = crate::matchlist();
+        let matcher = matchers.lookup(&indicated, fingerprint)?;
+        tracing::debug!(
+            "rule {} matched {}: {}",
+            matcher.rulename(),
+            matcher.describe(),
+            indicated
+        );
+        Some((matcher.rulename().to_string(), matcher.dispatcher().clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(json: serde_json::Value) -> MappingEntry {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn empty_downstreams_are_a_config_error() {
+        let tlsmap = TlsMap::new();
+        let empty = mapping(serde_json::json!({"exact": "a.example", "downstreams": []}));
+        let err = Dispatcher::from_mappingentry(&empty, &tlsmap).unwrap_err();
+        assert_eq!(err.to_string(), "downstreams is empty");
+        let some =
+            mapping(serde_json::json!({"exact": "a.example", "downstreams": ["127.0.0.1:9"]}));
+        assert!(Dispatcher::from_mappingentry(&some, &tlsmap).is_ok());
+
+        let cfg: Configuration = serde_json::from_value(serde_json::json!({
+            "listener": {"addr": "127.0.0.1", "port": 0},
+            "mapping": {},
+        }))
+        .unwrap();
+        let empty = mapping(serde_json::json!({"exact": "a.example", "http_downstreams": []}));
+        let err = Dispatcher::http_from_mappingentry(&empty, &cfg).unwrap_err();
+        assert_eq!(err.to_string(), "http_downstreams is empty");
     }
 }
@@ -0,0 +1,935 @@
+// Encrypted ClientHello, as the client-facing server in split mode
+// (RFC 9849): open the inner ClientHello, route on the name inside,
+// and hand the inner hello to a passthrough downstream, which does the
+// rest of ECH (acceptance signal and all) itself
+// rustls can't do the server half of ECH, so there's no terminating
+// an ECH connection here; those go to the fallback mapping instead
+// a HelloRetryRequest from the downstream means a second outer hello,
+// sealed with the same HPKE context, which gets opened the same way
+
+use anyhow::{Context, anyhow, bail};
+use base64::Engine;
+use rand::seq::IndexedRandom;
+use rustls::crypto::{
+    aws_lc_rs::hpke::{ALL_SUPPORTED_SUITES, DH_KEM_X25519_HKDF_SHA256_AES_128},
+    hpke::{EncapsulatedSecret, Hpke, HpkeOpener, HpkePrivateKey},
+};
+use rustls_pki_types::{EchConfigListBytes, pem::PemObject};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::alert::{self, AlertLevel};
+use crate::clienthello::{ClientHello, EXT_ECH, EXT_ECH_OUTER_EXTENSIONS, Reader, u16s};
+use crate::dispatcher::Dispatcher;
+use crate::fingerprint::Fingerprint;
+use crate::track::ConnInfo;
+
+const ECH_VERSION: u16 = 0xfe0d;
+const KEM_X25519: u16 = 0x0020;
+const KDF_HKDF_SHA256: u16 = 0x0001;
+const AEAD_AES_128_GCM: u16 = 0x0001;
+const AEAD_CHACHA20_POLY1305: u16 = 0x0003;
+// RFC 8446 4.1.3: a ServerHello with this random is a HelloRetryRequest
+const HRR_RANDOM: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+const RECORD_CHANGE_CIPHER_SPEC: u8 = 0x14;
+const RECORD_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_SERVER_HELLO: u8 = 0x02;
+// the most a second ClientHello, and any CCS before it, gets to be
+const MAX_RETRY_HELLO: usize = 65536;
+// PKCS#8 wrapping of a raw X25519 private key, RFC 8410
+const X25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x6e, 0x04, 0x22, 0x04, 0x20,
+];
+
+pub struct EchKeys {
+    configs: Vec<EchConfig>,
+    private_key: HpkePrivateKey,
+}
+
+struct EchConfig {
+    config_id: u8,
+    kem_id: u16,
+    // (kdf, aead) pairs
+    suites: Vec<(u16, u16)>,
+    // what an outer hello says when it means us
+    public_name: String,
+    // the whole ECHConfig, which HPKE wants verbatim as info
+    raw: Vec<u8>,
+}
+
+impl EchKeys {
+    // an "ECHConfig file": PRIVATE KEY (X25519, PKCS#8) and ECHCONFIG PEM
+    pub fn from_path(path: &str) -> anyhow::Result<EchKeys> {
+        let (config_list, key) = EchConfigListBytes::config_and_key_from_iter(
+            PemObject::pem_file_iter(path)
+                .map_err(|err| anyhow!("couldn't read {}: {}", path, err))?,
+        )
+        .map_err(|err| anyhow!("{}: {}", path, err))?;
+        let private_key = key
+            .secret_pkcs8_der()
+            .strip_prefix(&X25519_PKCS8_PREFIX[..])
+            .filter(|raw| raw.len() == 32)
+            .ok_or_else(|| anyhow!("{}: only X25519 ECH keys are supported", path))?;
+        let configs = parse_config_list(config_list.as_ref())
+            .with_context(|| format!("{}: bad ECHCONFIG", path))?;
+        if configs.is_empty() {
+            bail!("{}: no usable ECH configs (X25519, version 0xfe0d)", path);
+        }
+        Ok(EchKeys {
+            configs,
+            private_key: HpkePrivateKey::from(private_key.to_vec()),
+        })
+    }
+
+    pub fn is_public_name(&self, name: &str) -> bool {
+        let name = crate::matcher::normalize(name);
+        self.configs
+            .iter()
+            .any(|config| crate::matcher::normalize(&config.public_name) == name)
+    }
+
+    // Ok(None) when there's no ECH to speak of; the context comes back
+    // too, in case there's a second hello to open with it
+    pub fn open(&self, outer: &ClientHello) -> anyhow::Result<Option<(ClientHello, EchRetry)>> {
+        let Some(ech) = OuterEch::parse(outer)? else {
+            return Ok(None);
+        };
+        for config in self
+            .configs
+            .iter()
+            .filter(|config| config.config_id == ech.config_id)
+            .filter(|config| config.suites.contains(&ech.suite))
+        {
+            let Some(hpke) = ALL_SUPPORTED_SUITES.iter().find(|hpke| {
+                let suite = hpke.suite();
+                u16::from(suite.kem) == config.kem_id
+                    && (u16::from(suite.sym.kdf_id), u16::from(suite.sym.aead_id)) == ech.suite
+            }) else {
+                continue;
+            };
+            let mut info = b"tls ech\0".to_vec();
+            info.extend_from_slice(&config.raw);
+            let Ok(mut opener) = hpke.setup_opener(
+                &EncapsulatedSecret(ech.enc.to_vec()),
+                &info,
+                &self.private_key,
+            ) else {
+                continue;
+            };
+            if let Ok(encoded) = opener.open(&ech.aad, ech.payload) {
+                let retry = EchRetry {
+                    config_id: ech.config_id,
+                    suite: ech.suite,
+                    opener,
+                };
+                return Ok(Some((decode_inner(outer, encoded)?, retry)));
+            }
+        }
+        bail!("couldn't open ECH with config_id {}", ech.config_id)
+    }
+}
+
+// what opened the first hello, for the one after a HelloRetryRequest:
+// same config and suite, no enc, next sequence number (RFC 9849 6.1.5)
+pub struct EchRetry {
+    config_id: u8,
+    suite: (u16, u16),
+    opener: Box<dyn HpkeOpener>,
+}
+
+impl EchRetry {
+    pub fn open(&mut self, outer: &ClientHello) -> anyhow::Result<ClientHello> {
+        let ech = OuterEch::parse(outer)?.ok_or_else(|| anyhow!("second hello has no ECH"))?;
+        if ech.config_id != self.config_id || ech.suite != self.suite {
+            bail!("second hello switched ECH config or suite");
+        }
+        if !ech.enc.is_empty() {
+            bail!("second hello has an enc");
+        }
+        let encoded = self
+            .opener
+            .open(&ech.aad, ech.payload)
+            .map_err(|err| anyhow!("couldn't open second hello's ECH: {:?}", err))?;
+        decode_inner(outer, encoded)
+    }
+}
+
+// an outer hello's encrypted_client_hello extension
+struct OuterEch<'a> {
+    // (kdf, aead)
+    suite: (u16, u16),
+    config_id: u8,
+    enc: &'a [u8],
+    payload: &'a [u8],
+    // the whole outer body, payload zeroed
+    aad: Vec<u8>,
+}
+
+impl OuterEch<'_> {
+    // Ok(None) when there's none, or it's not an outer one
+    fn parse(outer: &ClientHello) -> anyhow::Result<Option<OuterEch<'_>>> {
+        let Some((_, at)) = outer.extensions.iter().find(|(ext, _)| *ext == EXT_ECH) else {
+            return Ok(None);
+        };
+        let ech = &outer.body[at.clone()];
+        let mut rd = Reader::new(ech);
+        // 0 is outer, 1 is inner and doesn't belong out here
+        if rd.u8()? != 0 {
+            return Ok(None);
+        }
+        let suite = (rd.u16()?, rd.u16()?);
+        let config_id = rd.u8()?;
+        let enc = rd.vec16()?;
+        let payload = rd.vec16()?;
+        let mut aad = outer.body.clone();
+        aad[at.start + payload.start..at.start + payload.end].fill(0);
+        Ok(Some(OuterEch {
+            suite,
+            config_id,
+            enc: &ech[enc],
+            payload: &ech[payload],
+            aad,
+        }))
+    }
+}
+
+fn parse_config_list(list: &[u8]) -> anyhow::Result<Vec<EchConfig>> {
+    let mut rd = Reader::new(list);
+    let all = rd.vec16()?;
+    let mut configs = Vec::new();
+    let mut rd = Reader::new(&list[..all.end]);
+    rd.pos = all.start;
+    while rd.pos < all.end {
+        let start = rd.pos;
+        let version = rd.u16()?;
+        let contents = rd.vec16()?;
+        // someone else's version, skip it
+        if version != ECH_VERSION {
+            continue;
+        }
+        let raw = list[start..contents.end].to_vec();
+        let data = &list[contents];
+        let mut crd = Reader::new(data);
+        let config_id = crd.u8()?;
+        let kem_id = crd.u16()?;
+        crd.vec16()?;
+        let suites = crd.vec16()?;
+        let suites: Vec<u16> = u16s(&data[suites]).collect();
+        crd.u8()?;
+        let public_name = crd.vec8()?;
+        let public_name = String::from_utf8(data[public_name].to_vec())?;
+        if kem_id != KEM_X25519 {
+            continue;
+        }
+        configs.push(EchConfig {
+            config_id,
+            kem_id,
+            suites: suites
+                .chunks_exact(2)
+                .map(|pair| (pair[0], pair[1]))
+                .collect(),
+            public_name,
+            raw,
+        });
+    }
+    Ok(configs)
+}
+
+// EncodedClientHelloInner back to the ClientHelloInner the client hashed
+fn decode_inner(outer: &ClientHello, encoded: Vec<u8>) -> anyhow::Result<ClientHello> {
+    let inner = ClientHello::parse_padded(encoded)?;
+    if !inner.session_id.is_empty() {
+        bail!("encoded inner hello has a session id");
+    }
+    if inner.extension(EXT_ECH) != Some(&[1][..]) {
+        bail!("inner hello isn't marked inner");
+    }
+    let mut body = Vec::with_capacity(outer.body.len());
+    body.extend_from_slice(&inner.body[..34]);
+    push_vec8(&mut body, &outer.body[outer.session_id.clone()]);
+    push_vec16(&mut body, &inner.body[inner.cipher_suites.clone()]);
+    push_vec8(&mut body, &inner.body[inner.compression.clone()]);
+    let mut extensions = Vec::new();
+    for (kind, at) in inner.extensions.iter() {
+        let data = &inner.body[at.clone()];
+        if *kind != EXT_ECH_OUTER_EXTENSIONS {
+            extensions.extend_from_slice(&kind.to_be_bytes());
+            push_vec16(&mut extensions, data);
+            continue;
+        }
+        // ech_outer_extensions: copy these in from the outer hello
+        let list = data
+            .split_first()
+            .filter(|(len, list)| **len as usize == list.len())
+            .map(|(_, list)| list)
+            .ok_or_else(|| anyhow!("bad ech_outer_extensions"))?;
+        for referenced in u16s(list) {
+            if referenced == EXT_ECH {
+                bail!("ech_outer_extensions references ech");
+            }
+            let copied = outer
+                .extension(referenced)
+                .ok_or_else(|| anyhow!("outer hello lacks extension {:#06x}", referenced))?;
+            extensions.extend_from_slice(&referenced.to_be_bytes());
+            push_vec16(&mut extensions, copied);
+        }
+    }
+    push_vec16(&mut body, &extensions);
+    let mut hello = ClientHello::parse_body(body)?;
+    hello.record_version = outer.record_version;
+    hello.records_len = outer.records_len;
+    Ok(hello)
+}
+
+fn push_vec8(out: &mut Vec<u8>, data: &[u8]) {
+    out.push(data.len() as u8);
+    out.extend_from_slice(data);
+}
+
+fn push_vec16(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+// a fresh X25519 key and ECHConfigList, as an "ECHConfig file", plus
+// the base64 ECHConfigList for the ech= of a DNS HTTPS record
+pub fn keygen(public_name: &str) -> anyhow::Result<(String, String)> {
+    if public_name.is_empty() || public_name.len() > 255 {
+        bail!("public name must be 1 to 255 bytes");
+    }
+    let (public_key, private_key) = DH_KEM_X25519_HKDF_SHA256_AES_128.generate_key_pair()?;
+    let mut contents = vec![rand::random::<u8>()];
+    contents.extend_from_slice(&KEM_X25519.to_be_bytes());
+    push_vec16(&mut contents, &public_key.0);
+    let suites: Vec<u8> = [
+        KDF_HKDF_SHA256,
+        AEAD_AES_128_GCM,
+        KDF_HKDF_SHA256,
+        AEAD_CHACHA20_POLY1305,
+    ]
+    .iter()
+    .flat_map(|id| id.to_be_bytes())
+    .collect();
+    push_vec16(&mut contents, &suites);
+    // maximum_name_length, 0 is "no idea"
+    contents.push(0);
+    push_vec8(&mut contents, public_name.as_bytes());
+    // no extensions
+    push_vec16(&mut contents, &[]);
+    let mut config = ECH_VERSION.to_be_bytes().to_vec();
+    push_vec16(&mut config, &contents);
+    let mut list = Vec::new();
+    push_vec16(&mut list, &config);
+
+    let mut pkcs8 = X25519_PKCS8_PREFIX.to_vec();
+    pkcs8.extend_from_slice(private_key.secret_bytes());
+    let file = pem::encode_many(&[
+        pem::Pem::new("PRIVATE KEY", pkcs8),
+        pem::Pem::new("ECHCONFIG", list.clone()),
+    ]);
+    Ok((file, base64::engine::general_purpose::STANDARD.encode(list)))
+}
+
+// Some(socket) back when it's not ECH meant for us, and the regular
+// SNI path should have it
+// fingerprints are the outer hello's, that's what came over the wire
+pub async fn dispatch(
+    socket: TcpStream,
+    outer: &ClientHello,
+    fingerprint: &Fingerprint,
+    keys: &EchKeys,
+    conn: &ConnInfo,
+) -> Option<TcpStream> {
+    let (inner, retry) = match keys.open(outer) {
+        Ok(None) => return Some(socket),
+        Ok(Some(opened)) => opened,
+        // GREASE, or somebody else's ECH, unless it was aimed at us
+        Err(err) => {
+            let ours = outer
+                .server_name()
+                .is_some_and(|name| keys.is_public_name(&name));
+            tracing::debug!("ech: {:#} (for us: {})", err, ours);
+            return if ours {
+                fallback(socket, conn).await.err()
+            } else {
+                Some(socket)
+            };
+        }
+    };
+
+    let indicated = inner.server_name().unwrap_or_default();
+    tracing::debug!("ech inner indicated: {:?}", indicated);
+    conn.sni.set(indicated.clone()).ok();
+    match Dispatcher::from_indicated(&indicated, Some(fingerprint)) {
+        Some((
+            rulename,
+            Dispatcher::TCPDownstreamDispatcher {
+                downstreams,
+                splice,
+            },
+        )) => {
+            conn.rule.set(rulename).ok();
+            let Some(chosen) = downstreams.choose(&mut rand::rng()) else {
+                tracing::error!("no downstreams in dispatcher");
+                return None;
+            };
+            conn.downstream.set(chosen.clone()).ok();
+            if let Err(err) = forward(socket, &inner, retry, chosen, splice, conn).await {
+                tracing::debug!("ech forward termination: {:?}", err);
+            }
+            None
+        }
+        // turned away by name, the same with or without ECH
+        Some((
+            rulename,
+            dispatcher @ (Dispatcher::TLSAlertDispatcher { .. }
+            | Dispatcher::CloseDispatcher { .. }),
+        )) => {
+            conn.rule.set(rulename).ok();
+            dispatcher.do_dispatch(socket, conn).await;
+            None
+        }
+        Some((rulename, _)) => {
+            let socket = fallback(socket, conn).await.err()?;
+            // no server side of ECH in rustls, so no terminating it here
+            tracing::warn!(
+                "ech for {} lands on rule {}, which terminates TLS; refusing",
+                indicated,
+                rulename
+            );
+            conn.rule.set(rulename).ok();
+            let refusal = Dispatcher::TLSAlertDispatcher {
+                alert_level: AlertLevel::Fatal,
+                alert_description: rustls::AlertDescription::HandshakeFailure,
+            };
+            refusal.do_dispatch(socket, conn).await;
+            None
+        }
+        None => fallback(socket, conn).await.err(),
+    }
+}
+
+// the inner hello goes out in place of the outer, and again for the
+// second one if the downstream answers with a HelloRetryRequest, then
+// it's just bytes
+async fn forward(
+    mut socket: TcpStream,
+    inner: &ClientHello,
+    mut retry: EchRetry,
+    downstream: &str,
+    splice: bool,
+    conn: &ConnInfo,
+) -> std::io::Result<()> {
+    let mut upstream = crate::track::connect_downstream(downstream).await?;
+    upstream.write_all(&inner.to_records()).await?;
+    let mut outer = vec![0; inner.records_len];
+    socket.read_exact(&mut outer).await?;
+
+    let reply = read_server_hello(&mut upstream).await?;
+    socket.write_all(&reply).await?;
+    if is_hello_retry(&reply) {
+        tracing::debug!("ech: HelloRetryRequest, opening the second hello");
+        let (ccs, second, rest) = read_second_hello(&mut socket).await?;
+        match second.and_then(|second| retry.open(&second)) {
+            Ok(second_inner) => {
+                upstream.write_all(&ccs).await?;
+                upstream.write_all(&second_inner.to_records()).await?;
+                upstream.write_all(&rest).await?;
+            }
+            Err(err) => {
+                // RFC 9849 7.1: a retry that won't decrypt is decrypt_error
+                alert::send(
+                    &mut socket,
+                    AlertLevel::Fatal,
+                    rustls::AlertDescription::DecryptError,
+                    inner.record_version,
+                )
+                .await
+                .ok();
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{:#}", err),
+                ));
+            }
+        }
+    }
+    crate::proxy::tcp_proxy_stream(socket, upstream, splice, conn).await
+}
+
+// whatever the downstream says first, at least as far as the
+// ServerHello's random, unless it hangs up sooner
+async fn read_server_hello(upstream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut reply = Vec::new();
+    let mut buf = [0; 4096];
+    while reply.len() < 43 {
+        let read = upstream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        reply.extend_from_slice(&buf[..read]);
+    }
+    Ok(reply)
+}
+
+// record header, handshake header, legacy_version, then the random; a
+// ServerHello fragmented smaller than that isn't worth piecing together
+fn is_hello_retry(reply: &[u8]) -> bool {
+    match reply {
+        [
+            RECORD_HANDSHAKE,
+            _,
+            _,
+            len_hi,
+            len_lo,
+            HANDSHAKE_SERVER_HELLO,
+            rest @ ..,
+        ] => {
+            u16::from_be_bytes([*len_hi, *len_lo]) >= 38 && rest.get(5..37) == Some(&HRR_RANDOM[..])
+        }
+        _ => false,
+    }
+}
+
+// the client's next flight: any ChangeCipherSpec records (middlebox
+// compatibility), the second outer hello, and whatever came after it
+async fn read_second_hello(
+    socket: &mut TcpStream,
+) -> std::io::Result<(Vec<u8>, anyhow::Result<ClientHello>, Vec<u8>)> {
+    let mut flight = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let parsed = match ccs_prefix(&flight) {
+            Some(ccs_len) => ClientHello::parse_records(&flight[ccs_len..])
+                .map(|second| second.map(|second| (ccs_len, second))),
+            None => Ok(None),
+        };
+        let (ccs_len, second) = match parsed {
+            Ok(Some(parsed)) => parsed,
+            Ok(None) if flight.len() < MAX_RETRY_HELLO => {
+                let read = socket.read(&mut buf).await?;
+                if read == 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                flight.extend_from_slice(&buf[..read]);
+                continue;
+            }
+            Ok(None) => {
+                let err = anyhow!("second ClientHello bigger than {} bytes", MAX_RETRY_HELLO);
+                return Ok((Vec::new(), Err(err), Vec::new()));
+            }
+            Err(err) => return Ok((Vec::new(), Err(err), Vec::new())),
+        };
+        let rest = flight.split_off(ccs_len + second.records_len);
+        flight.truncate(ccs_len);
+        return Ok((flight, Ok(second), rest));
+    }
+}
+
+// how much of the front is whole ChangeCipherSpec records, None while
+// one of them is still arriving
+fn ccs_prefix(flight: &[u8]) -> Option<usize> {
+    let mut len = 0;
+    while flight.get(len) == Some(&RECORD_CHANGE_CIPHER_SPEC) {
+        let header = flight.get(len..len + 5)?;
+        len += 5 + u16::from_be_bytes([header[3], header[4]]) as usize;
+        if flight.len() < len {
+            return None;
+        }
+    }
+    Some(len)
+}
+
+// the [ech] fallback mapping has it if there is one; Err(socket) back
+// when there isn't, to be routed on the outer name as usual
+async fn fallback(socket: TcpStream, conn: &ConnInfo) -> Result<(), TcpStream> {
+    let cfg = crate::fullcfg();
+    let Some(name) = cfg.ech.as_ref().and_then(|ech| ech.fallback.as_ref()) else {
+        return Err(socket);
+    };
+    let matchers = crate::matchlist();
+    let Some(matcher) = matchers.iter().find(|matcher| matcher.rulename() == name) else {
+        tracing::warn!("ech fallback mapping {} went missing", name);
+        return Err(socket);
+    };
+    conn.rule.set(name.clone()).ok();
+    matcher.dispatcher().do_dispatch(socket, conn).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use rustls::crypto::hpke::{HpkePublicKey, HpkeSealer};
+
+    use super::*;
+    use crate::clienthello::{
+        EXT_ALPN, EXT_SERVER_NAME, EXT_SUPPORTED_GROUPS,
+        tests::{body, server_name},
+    };
+
+    const GROUPS: &[u8] = &[0, 4, 0x00, 0x1d, 0x00, 0x17];
+    const ALPN: &[u8] = &[0, 3, 2, b'h', b'2'];
+
+    // EncodedClientHelloInner: no session id, groups and ALPN by
+    // reference to the outer hello, padded out with zeros
+    fn encoded_inner(name: &str) -> Vec<u8> {
+        let outer_exts = [4, 0x00, 0x0a, 0x00, 0x10];
+        let mut encoded = body(
+            &[],
+            &[
+                (EXT_SERVER_NAME, &server_name(name)),
+                (EXT_ECH_OUTER_EXTENSIONS, &outer_exts),
+                (EXT_ECH, &[1]),
+            ],
+        );
+        encoded.extend_from_slice(&[0; 31]);
+        encoded
+    }
+
+    fn outer_extension(suite: (u16, u16), config_id: u8, enc: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut ext = vec![0];
+        ext.extend_from_slice(&suite.0.to_be_bytes());
+        ext.extend_from_slice(&suite.1.to_be_bytes());
+        ext.push(config_id);
+        push_vec16(&mut ext, enc);
+        push_vec16(&mut ext, payload);
+        ext
+    }
+
+    fn outer_hello(ech: &[u8]) -> ClientHello {
+        ClientHello::parse_body(body(
+            &[9; 32],
+            &[
+                (EXT_SERVER_NAME, &server_name("public.example")),
+                (EXT_SUPPORTED_GROUPS, GROUPS),
+                (EXT_ALPN, ALPN),
+                (EXT_ECH, ech),
+            ],
+        ))
+        .unwrap()
+    }
+
+    // the client half: an outer hello whose payload is sealed over the
+    // outer hello with that payload zeroed
+    fn seal(
+        sealer: &mut Box<dyn HpkeSealer>,
+        config_id: u8,
+        enc: &[u8],
+        inner: &[u8],
+    ) -> ClientHello {
+        let suite = (KDF_HKDF_SHA256, AEAD_AES_128_GCM);
+        let zeros = vec![0; inner.len() + 16];
+        let aad = outer_hello(&outer_extension(suite, config_id, enc, &zeros)).body;
+        let payload = sealer.seal(&aad, inner).unwrap();
+        outer_hello(&outer_extension(suite, config_id, enc, &payload))
+    }
+
+    // the client's end of HPKE for the first config
+    fn sealer(keys: &EchKeys) -> (EncapsulatedSecret, Box<dyn HpkeSealer>) {
+        let config = &keys.configs[0];
+        // version, length, config_id, kem_id, then the public key
+        let public_key = Reader::new(&config.raw[7..]).vec16().unwrap();
+        let public_key = HpkePublicKey(config.raw[7..][public_key].to_vec());
+        let mut info = b"tls ech\0".to_vec();
+        info.extend_from_slice(&config.raw);
+        DH_KEM_X25519_HKDF_SHA256_AES_128
+            .setup_sealer(&info, &public_key)
+            .unwrap()
+    }
+
+    #[test]
+    fn decodes_inner_with_outer_extensions() {
+        let outer = outer_hello(&[0]);
+        let inner = decode_inner(&outer, encoded_inner("secret.example")).unwrap();
+        assert_eq!(inner.server_name().as_deref(), Some("secret.example"));
+        // the outer session id, the referenced extensions in their place
+        assert_eq!(&inner.body[inner.session_id.clone()], &[9; 32]);
+        let kinds: Vec<u16> = inner.extensions.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(
+            kinds,
+            [EXT_SERVER_NAME, EXT_SUPPORTED_GROUPS, EXT_ALPN, EXT_ECH]
+        );
+        assert_eq!(inner.extension(EXT_SUPPORTED_GROUPS), Some(GROUPS));
+        assert_eq!(inner.extension(EXT_ALPN), Some(ALPN));
+        assert_eq!(inner.extension(EXT_ECH), Some(&[1][..]));
+    }
+
+    #[test]
+    fn refuses_bad_outer_references() {
+        let outer = ClientHello::parse_body(body(&[], &[(EXT_ECH, &[0])])).unwrap();
+        // outer hello lacks supported_groups
+        assert!(decode_inner(&outer, encoded_inner("secret.example")).is_err());
+        let ech_itself = body(
+            &[],
+            &[
+                (EXT_ECH_OUTER_EXTENSIONS, &[2, 0xfe, 0x0d]),
+                (EXT_ECH, &[1]),
+            ],
+        );
+        assert!(decode_inner(&outer, ech_itself).is_err());
+        let not_inner = body(&[], &[(EXT_SERVER_NAME, &server_name("x.example"))]);
+        assert!(decode_inner(&outer, not_inner).is_err());
+    }
+
+    #[test]
+    fn keygen_then_open() {
+        let (file, _) = keygen("public.example").unwrap();
+        let path = std::env::temp_dir().join(format!("lurkr-ech-{}.pem", std::process::id()));
+        std::fs::write(&path, file).unwrap();
+        let keys = EchKeys::from_path(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let keys = keys.unwrap();
+        assert!(keys.is_public_name("Public.Example."));
+
+        let config = &keys.configs[0];
+        let (enc, mut sealer) = sealer(&keys);
+
+        let outer = seal(
+            &mut sealer,
+            config.config_id,
+            &enc.0,
+            &encoded_inner("secret.example"),
+        );
+        let (inner, mut retry) = keys.open(&outer).unwrap().unwrap();
+        assert_eq!(inner.server_name().as_deref(), Some("secret.example"));
+        assert_eq!(inner.extension(EXT_ALPN), Some(ALPN));
+
+        // after a HelloRetryRequest: same context, no enc
+        let second = seal(
+            &mut sealer,
+            config.config_id,
+            &[],
+            &encoded_inner("retry.example"),
+        );
+        assert!(
+            retry.open(&outer).is_err(),
+            "first hello again, with an enc"
+        );
+        let inner = retry.open(&second).unwrap();
+        assert_eq!(inner.server_name().as_deref(), Some("retry.example"));
+        assert!(retry.open(&second).is_err(), "sequence number moved on");
+
+        // sealed for some other config
+        let wrong_id = seal(
+            &mut sealer,
+            config.config_id.wrapping_add(1),
+            &enc.0,
+            &encoded_inner("secret.example"),
+        );
+        assert!(keys.open(&wrong_id).is_err());
+        assert!(keys.open(&outer_hello(&[1])).unwrap().is_none());
+    }
+
+    #[test]
+    fn spots_hello_retry_requests() {
+        let mut hrr = vec![
+            RECORD_HANDSHAKE,
+            3,
+            3,
+            0,
+            38 + 4,
+            HANDSHAKE_SERVER_HELLO,
+            0,
+            0,
+            38,
+        ];
+        hrr.extend_from_slice(&[3, 3]);
+        hrr.extend_from_slice(&HRR_RANDOM);
+        assert!(is_hello_retry(&hrr));
+        let mut server_hello = hrr.clone();
+        server_hello[20] ^= 1;
+        assert!(!is_hello_retry(&server_hello));
+        assert!(!is_hello_retry(&hrr[..hrr.len() - 1]));
+        assert!(!is_hello_retry(&[0x15, 3, 3, 0, 2, 2, 40]));
+    }
+
+    // what read_second_hello makes of these arriving in these writes
+    async fn second_flight(writes: Vec<Vec<u8>>) -> (Vec<u8>, ClientHello, Vec<u8>) {
+        let lsnr = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(lsnr.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut socket, _) = lsnr.accept().await.unwrap();
+        tokio::spawn(async move {
+            for write in writes {
+                client.write_all(&write).await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+            client
+        });
+        let (ccs, second, rest) = read_second_hello(&mut socket).await.unwrap();
+        (ccs, second.unwrap(), rest)
+    }
+
+    #[tokio::test]
+    async fn splits_the_second_flight() {
+        let hello = outer_hello(&[0]).to_records();
+        let ccs = [RECORD_CHANGE_CIPHER_SPEC, 3, 3, 0, 1, 1];
+        let early = [0x17, 3, 3, 0, 1, 0xff];
+        let mut flight = ccs.to_vec();
+        flight.extend_from_slice(&hello);
+
+        // a byte at a time, so every partial state gets seen
+        let (got_ccs, second, rest) =
+            second_flight(flight.iter().map(|byte| vec![*byte]).collect()).await;
+        assert_eq!(got_ccs, ccs);
+        assert_eq!(second.to_records(), hello);
+        assert!(rest.is_empty());
+
+        // all at once, with something after it
+        flight.extend_from_slice(&early);
+        let (got_ccs, second, rest) = second_flight(vec![flight]).await;
+        assert_eq!(got_ccs, ccs);
+        assert_eq!(second.to_records(), hello);
+        assert_eq!(rest, early);
+
+        // no CCS at all
+        let (got_ccs, second, _) = second_flight(vec![hello.clone()]).await;
+        assert!(got_ccs.is_empty());
+        assert_eq!(second.to_records(), hello);
+    }
+
+    // keys and mappings made live: one that alerts, one that
+    // terminates, and the fallback when asked for
+    fn install(test: &str, fallback: Option<&str>) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lurkr-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let key_path = dir.join("ech.pem");
+        std::fs::write(&key_path, keygen("public.example").unwrap().0).unwrap();
+        let fallback = fallback
+            .map(|name| format!("fallback = \"{}\"", name))
+            .unwrap_or_default();
+        let config = format!(
+            r#"
+[listener]
+addr = "127.0.0.1"
+port = 0
+
+[ech]
+key_path = "{}"
+{}
+
+[tls.anon]
+
+[mapping.refused]
+exact = "refused.example"
+alert = "access_denied"
+
+[mapping.terminated]
+exact = "terminated.example"
+tls = "anon"
+response_code = 200
+response_body = "hi"
+
+[mapping.turnedaway]
+exact = "turnedaway.example"
+alert = "unrecognized_name"
+"#,
+            key_path.display(),
+            fallback
+        );
+        crate::install_for_test(&dir, &config);
+        dir
+    }
+
+    // ech::dispatch on a fresh connection, and whatever the client got
+    // back once it was handled (None if it wasn't)
+    async fn dispatched(outer: &ClientHello) -> (Option<TcpStream>, Option<Vec<u8>>) {
+        let lsnr = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(lsnr.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, peer) = lsnr.accept().await.unwrap();
+        // enough of a record header for the alert's version
+        client.write_all(&[RECORD_HANDSHAKE, 3, 1]).await.unwrap();
+        let keys = crate::ECH.read().unwrap().clone().unwrap();
+        let conn = crate::track::Tracked::new(peer);
+        let socket = dispatch(socket, outer, &Fingerprint::of(outer), &keys, &conn).await;
+        if socket.is_some() {
+            return (socket, None);
+        }
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).await.unwrap();
+        (None, Some(reply))
+    }
+
+    fn alert_description(reply: &[u8]) -> u8 {
+        assert_eq!(reply.len(), 7, "{:?}", reply);
+        assert_eq!(reply[0], 21);
+        reply[6]
+    }
+
+    #[tokio::test]
+    async fn unopened_ech_goes_to_the_fallback_if_any() {
+        let _globals = crate::TEST_GLOBALS.lock().await;
+        let dir = install("ech-no-fallback", None);
+        let keys = crate::ECH.read().unwrap().clone().unwrap();
+        let (enc, mut sealer) = sealer(&keys);
+        let config_id = keys.configs[0].config_id.wrapping_add(1);
+        let outer = seal(&mut sealer, config_id, &enc.0, &encoded_inner("x.example"));
+        // aimed at our public name, but nothing to fall back on: routed
+        // on the outer name like any other hello
+        let (socket, _) = dispatched(&outer).await;
+        assert!(socket.is_some());
+
+        install("ech-no-fallback", Some("turnedaway"));
+        let (socket, reply) = dispatched(&outer).await;
+        assert!(socket.is_none());
+        let description = alert_description(&reply.unwrap());
+        assert_eq!(
+            description,
+            u8::from(rustls::AlertDescription::UnrecognisedName)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn inner_names_get_turned_away_too() {
+        let _globals = crate::TEST_GLOBALS.lock().await;
+        let dir = install("ech-inner-rules", None);
+        let keys = crate::ECH.read().unwrap().clone().unwrap();
+        let config_id = keys.configs[0].config_id;
+        let sealed = |name: &str| {
+            let (enc, mut sealer) = sealer(&keys);
+            seal(&mut sealer, config_id, &enc.0, &encoded_inner(name))
+        };
+
+        let (_, reply) = dispatched(&sealed("refused.example")).await;
+        let description = alert_description(&reply.unwrap());
+        assert_eq!(
+            description,
+            u8::from(rustls::AlertDescription::AccessDenied)
+        );
+
+        // can't terminate it, and no fallback to take it instead
+        let (_, reply) = dispatched(&sealed("terminated.example")).await;
+        let description = alert_description(&reply.unwrap());
+        assert_eq!(
+            description,
+            u8::from(rustls::AlertDescription::HandshakeFailure)
+        );
+
+        install("ech-inner-rules", Some("turnedaway"));
+        let keys = crate::ECH.read().unwrap().clone().unwrap();
+        let (enc, mut sealer) = sealer(&keys);
+        let outer = seal(
+            &mut sealer,
+            keys.configs[0].config_id,
+            &enc.0,
+            &encoded_inner("terminated.example"),
+        );
+        let (_, reply) = dispatched(&outer).await;
+        let description = alert_description(&reply.unwrap());
+        assert_eq!(
+            description,
+            u8::from(rustls::AlertDescription::UnrecognisedName)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
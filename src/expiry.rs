@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{LazyLock, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rustls::pki_types::CertificateDer;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::conf::{Configuration, Diagnostics, Expiry};
+
+pub const DEFAULT_WARN_DAYS: [u64; 5] = [30, 14, 7, 3, 1];
+pub const DEFAULT_CHECK_SECS: u64 = 3600;
+
+// every certificate the live config serves or trusts client certs by
+pub static WATCHED: RwLock<Vec<Watched>> = RwLock::new(Vec::new());
+
+// the tightest threshold each cert has already been complained about at,
+// so a cert gets one warning per threshold; survives reloads, and a
+// renewed cert has a new notAfter so starts over
+static WARNED: LazyLock<Mutex<HashMap<(String, i64), u64>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    // in a tls config's served chain
+    Server,
+    // in its client_certbundle
+    ClientCa,
+}
+
+impl Role {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Role::Server => "server",
+            Role::ClientCa => "client_ca",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Watched {
+    pub tls: String,
+    pub role: Role,
+    // the file (or wherever) it was loaded from
+    pub source: String,
+    pub subject: String,
+    pub serial: String,
+    // unix seconds
+    pub not_before: i64,
+    pub not_after: i64,
+}
+
+impl Watched {
+    // the certs that parse, tls name left for the caller
+    pub fn from_certs(certs: &[CertificateDer<'_>], role: Role, source: &str) -> Vec<Watched> {
+        certs
+            .iter()
+            .filter_map(|cert| X509Certificate::from_der(cert).ok())
+            .map(|(_, cert)| Watched {
+                tls: String::new(),
+                role,
+                source: source.to_string(),
+                subject: cert.subject().to_string(),
+                serial: cert.raw_serial_as_string(),
+                not_before: cert.validity().not_before.timestamp(),
+                not_after: cert.validity().not_after.timestamp(),
+            })
+            .collect()
+    }
+
+    pub fn days_left(&self, now: i64) -> f64 {
+        (self.not_after - now) as f64 / 86400.0
+    }
+
+    fn key(&self) -> (String, i64) {
+        (
+            format!("{}/{}/{}", self.tls, self.role.as_str(), self.serial),
+            self.not_after,
+        )
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "tls config {}: {} certificate {} from {}",
+            self.tls,
+            self.role.as_str(),
+            self.subject,
+            self.source
+        )
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn warn_days(expiry: Option<&Expiry>) -> Vec<u64> {
+    expiry
+        .and_then(|expiry| expiry.warn_days.clone())
+        .unwrap_or_else(|| DEFAULT_WARN_DAYS.to_vec())
+}
+
+// the tightest threshold a cert with this long left has gone past
+fn crossed(days_left: f64, thresholds: &[u64]) -> Option<u64> {
+    thresholds
+        .iter()
+        .copied()
+        .filter(|&days| days_left < days as f64)
+        .min()
+}
+
+// at (re)load: expired is an error with refuse_expired and a warning
+// without, not yet valid and inside a threshold are warnings
+pub fn assess(cfg: &Configuration, watched: &[Watched], diag: &mut Diagnostics) {
+    let refuse = cfg
+        .expiry
+        .as_ref()
+        .and_then(|expiry| expiry.refuse_expired)
+        .unwrap_or(false);
+    let thresholds = warn_days(cfg.expiry.as_ref());
+    let now = unix_now();
+    for cert in watched {
+        if cert.not_after < now {
+            let msg = format!(
+                "{} expired {} days ago",
+                cert.describe(),
+                -cert.days_left(now) as i64
+            );
+            if refuse {
+                diag.error(msg);
+            } else {
+                diag.warn(msg);
+            }
+        } else if cert.not_before > now {
+            diag.warn(format!(
+                "{} isn't valid for another {} days",
+                cert.describe(),
+                (cert.not_before - now) / 86400
+            ));
+        } else if crossed(cert.days_left(now), &thresholds).is_some() {
+            diag.warn(format!(
+                "{} expires in {} days",
+                cert.describe(),
+                cert.days_left(now) as i64
+            ));
+        }
+    }
+}
+
+// make these the live set; whatever assess() just said counts as said
+pub fn install(cfg: &Configuration, watched: Vec<Watched>) {
+    let thresholds = warn_days(cfg.expiry.as_ref());
+    let now = unix_now();
+    let mut warned = WARNED.lock().unwrap();
+    for cert in watched.iter() {
+        if let Some(days) = crossed(cert.days_left(now), &thresholds) {
+            warned.insert(cert.key(), days);
+        }
+    }
+    *WATCHED.write().unwrap() = watched;
+}
+
+// log certs that have gone past another threshold since last time,
+// and expired ones every time
+pub fn check(cfg: &Configuration) {
+    let thresholds = warn_days(cfg.expiry.as_ref());
+    let now = unix_now();
+    let mut warned = WARNED.lock().unwrap();
+    for cert in WATCHED.read().unwrap().iter() {
+        if cert.not_after < now {
+            tracing::error!("{} has expired", cert.describe());
+            continue;
+        }
+        let Some(days) = crossed(cert.days_left(now), &thresholds) else {
+            continue;
+        };
+        if warned
+            .get(&cert.key())
+            .is_some_and(|&before| before <= days)
+        {
+            continue;
+        }
+        tracing::warn!(
+            "{} expires in {} days",
+            cert.describe(),
+            cert.days_left(now) as i64
+        );
+        warned.insert(cert.key(), days);
+    }
+}
+
+// looks every [expiry] check_secs, picking up reloads as it goes
+pub async fn monitor() {
+    loop {
+        let every = crate::fullcfg()
+            .expiry
+            .as_ref()
+            .and_then(|expiry| expiry.check_secs)
+            .unwrap_or(DEFAULT_CHECK_SECS)
+            .max(1);
+        tokio::time::sleep(Duration::from_secs(every)).await;
+        check(&crate::fullcfg());
+    }
+}
+
+// Prometheus text exposition, one gauge per watched cert
+pub fn metrics() -> String {
+    let now = unix_now();
+    let mut out = String::from(
+        "# HELP lurkr_cert_expiry_days Days until a loaded certificate's notAfter, negative once expired\n\
+         # TYPE lurkr_cert_expiry_days gauge\n",
+    );
+    for cert in WATCHED.read().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "lurkr_cert_expiry_days{{tls=\"{}\",role=\"{}\",subject=\"{}\",serial=\"{}\"}} {:.3}",
+            label(&cert.tls),
+            cert.role.as_str(),
+            label(&cert.subject),
+            label(&cert.serial),
+            cert.days_left(now)
+        );
+    }
+    out
+}
+
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,280 @@
+// who's knocking, going by how their ClientHello is put together
+// JA3: https://github.com/salesforce/ja3
+// JA4: https://github.com/FoxIO-LLC/ja4 (the TLS one, "t" for TCP)
+
+use std::collections::HashSet;
+
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::clienthello::{
+    ClientHello, EXT_ALPN, EXT_EC_POINT_FORMATS, EXT_SERVER_NAME, EXT_SIGNATURE_ALGORITHMS,
+    EXT_SUPPORTED_GROUPS, EXT_SUPPORTED_VERSIONS, is_grease, u16s,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Fingerprint {
+    pub ja3: String,
+    pub ja4: String,
+}
+
+impl Fingerprint {
+    pub fn of(hello: &ClientHello) -> Fingerprint {
+        Fingerprint {
+            ja3: ja3(hello),
+            ja4: ja4(hello),
+        }
+    }
+}
+
+fn decimals(values: impl Iterator<Item = u16>) -> String {
+    values
+        .filter(|value| !is_grease(*value))
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// SSLVersion,Ciphers,Extensions,EllipticCurves,EllipticCurvePointFormats
+fn ja3(hello: &ClientHello) -> String {
+    let groups = hello
+        .extension(EXT_SUPPORTED_GROUPS)
+        .and_then(|data| data.get(2..))
+        .unwrap_or_default();
+    let point_formats = hello
+        .extension(EXT_EC_POINT_FORMATS)
+        .and_then(|data| data.get(1..))
+        .unwrap_or_default();
+    let full = format!(
+        "{},{},{},{},{}",
+        hello.legacy_version,
+        decimals(hello.cipher_suites()),
+        decimals(hello.extensions.iter().map(|(kind, _)| *kind)),
+        decimals(u16s(groups)),
+        decimals(point_formats.iter().map(|format| *format as u16)),
+    );
+    format!("{:x}", md5::compute(full))
+}
+
+fn truncated_sha256(data: &str) -> String {
+    if data.is_empty() {
+        return "000000000000".to_string();
+    }
+    Sha256::digest(data.as_bytes())
+        .iter()
+        .take(6)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn hex_list(values: impl Iterator<Item = u16>) -> String {
+    values
+        .map(|value| format!("{:04x}", value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// t{version}{d|i}{ciphers}{extensions}{alpn}_{cipher hash}_{extension hash}
+fn ja4(hello: &ClientHello) -> String {
+    let version = hello
+        .extension(EXT_SUPPORTED_VERSIONS)
+        .and_then(|data| data.get(1..))
+        .and_then(|versions| u16s(versions).filter(|v| !is_grease(*v)).max())
+        .unwrap_or(hello.legacy_version);
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        _ => "00",
+    };
+    let sni = if hello.extension(EXT_SERVER_NAME).is_some() {
+        'd'
+    } else {
+        'i'
+    };
+
+    let mut ciphers: Vec<u16> = hello.cipher_suites().filter(|c| !is_grease(*c)).collect();
+    let extensions: Vec<u16> = hello
+        .extensions
+        .iter()
+        .map(|(kind, _)| *kind)
+        .filter(|kind| !is_grease(*kind))
+        .collect();
+
+    let alpn = match hello.alpn().first().copied() {
+        Some(&[first, .., last])
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() =>
+        {
+            format!("{}{}", first as char, last as char)
+        }
+        Some(&[only]) if only.is_ascii_alphanumeric() => {
+            format!("{}{}", only as char, only as char)
+        }
+        Some(protocol) if !protocol.is_empty() => {
+            let first = format!("{:02x}", protocol[0]);
+            let last = format!("{:02x}", protocol[protocol.len() - 1]);
+            format!("{}{}", &first[..1], &last[1..])
+        }
+        _ => "00".to_string(),
+    };
+
+    ciphers.sort_unstable();
+    let mut sorted_extensions: Vec<u16> = extensions
+        .iter()
+        .copied()
+        .filter(|kind| *kind != EXT_SERVER_NAME && *kind != EXT_ALPN)
+        .collect();
+    sorted_extensions.sort_unstable();
+    let signature_algorithms = hello
+        .extension(EXT_SIGNATURE_ALGORITHMS)
+        .and_then(|data| data.get(2..))
+        .unwrap_or_default();
+    let mut extension_input = hex_list(sorted_extensions.into_iter());
+    if !signature_algorithms.is_empty() {
+        extension_input = format!(
+            "{}_{}",
+            extension_input,
+            hex_list(u16s(signature_algorithms))
+        );
+    }
+
+    format!(
+        "t{}{}{:02}{:02}{}_{}_{}",
+        version,
+        sni,
+        ciphers.len().min(99),
+        extensions.len().min(99),
+        alpn,
+        truncated_sha256(&hex_list(ciphers.into_iter())),
+        truncated_sha256(&extension_input),
+    )
+}
+
+// a mapping's fingerprint lists: either one matching is enough
+#[derive(Debug, Default)]
+pub struct FingerprintAcl {
+    pub ja3: HashSet<String>,
+    pub ja4: HashSet<String>,
+}
+
+impl FingerprintAcl {
+    pub fn permits(&self, fingerprint: &Fingerprint) -> bool {
+        self.ja3.contains(&fingerprint.ja3) || self.ja4.contains(&fingerprint.ja4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clienthello::tests::{body, server_name};
+
+    fn u16_list(len_bytes: usize, values: &[u16]) -> Vec<u8> {
+        let len = (values.len() * 2) as u16;
+        let mut out = len.to_be_bytes()[2 - len_bytes..].to_vec();
+        out.extend(values.iter().flat_map(|value| value.to_be_bytes()));
+        out
+    }
+
+    fn hello(legacy_version: u16, ciphers: &[u16], extensions: &[(u16, Vec<u8>)]) -> ClientHello {
+        let extensions: Vec<(u16, &[u8])> = extensions
+            .iter()
+            .map(|(kind, data)| (*kind, data.as_slice()))
+            .collect();
+        let mut body = body(&[], &extensions);
+        body[..2].copy_from_slice(&legacy_version.to_be_bytes());
+        // swap in these cipher suites for the builder's
+        let at = ClientHello::parse_body(body.clone()).unwrap().cipher_suites;
+        body.splice(at.start - 2..at.end, u16_list(2, ciphers));
+        ClientHello::parse_body(body).unwrap()
+    }
+
+    // the example in the JA3 README:
+    // 769,47-53-5-10-49161-49162-49171-49172-50-56-19-4,0-10-11,23-24-25,0
+    fn ja3_readme(grease: bool) -> ClientHello {
+        let mut ciphers = vec![47, 53, 5, 10, 49161, 49162, 49171, 49172, 50, 56, 19, 4];
+        let mut groups = vec![23, 24, 25];
+        let mut extensions = vec![];
+        if grease {
+            ciphers.insert(0, 0x3a3a);
+            groups.insert(0, 0x5a5a);
+            extensions.push((0x8a8a, vec![]));
+        }
+        extensions.extend([
+            (EXT_SERVER_NAME, server_name("example.com")),
+            (EXT_SUPPORTED_GROUPS, u16_list(2, &groups)),
+            (EXT_EC_POINT_FORMATS, vec![1, 0]),
+        ]);
+        hello(0x0301, &ciphers, &extensions)
+    }
+
+    #[test]
+    fn ja3_known_answer() {
+        assert_eq!(ja3(&ja3_readme(false)), "ada70206e40642a3e4461f35503241d5");
+        // GREASE anywhere doesn't count
+        assert_eq!(ja3(&ja3_readme(true)), "ada70206e40642a3e4461f35503241d5");
+    }
+
+    // the Chrome example in the JA4 technical details, GREASE and all:
+    // t13d1516h2_8daaf6152771_e5627efa2ab1
+    fn ja4_chrome() -> ClientHello {
+        let ciphers = [
+            0x0a0a, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013,
+            0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+        ];
+        let alpn = [
+            0, 12, 2, b'h', b'2', 8, b'h', b't', b't', b'p', b'/', b'1', b'.', b'1',
+        ];
+        let signature_algorithms = [
+            0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+        ];
+        let extensions = vec![
+            (0x1a1a, vec![]),
+            (EXT_SERVER_NAME, server_name("example.com")),
+            (0x0017, vec![]),
+            (0xff01, vec![0]),
+            (
+                EXT_SUPPORTED_GROUPS,
+                u16_list(2, &[0x2a2a, 0x001d, 0x0017, 0x0018]),
+            ),
+            (EXT_EC_POINT_FORMATS, vec![1, 0]),
+            (0x0023, vec![]),
+            (EXT_ALPN, alpn.to_vec()),
+            (0x0005, vec![1, 0, 0, 0, 0]),
+            (EXT_SIGNATURE_ALGORITHMS, u16_list(2, &signature_algorithms)),
+            (0x0012, vec![]),
+            (0x0033, vec![0, 0]),
+            (0x002d, vec![1, 1]),
+            (
+                EXT_SUPPORTED_VERSIONS,
+                u16_list(1, &[0x3a3a, 0x0304, 0x0303]),
+            ),
+            (0x001b, vec![2, 0, 2]),
+            (0x4469, vec![0, 3, 2, b'h', b'2']),
+            (0x4a4a, vec![0]),
+            (0x0015, vec![0; 16]),
+        ];
+        hello(0x0303, &ciphers, &extensions)
+    }
+
+    #[test]
+    fn ja4_known_answer() {
+        assert_eq!(ja4(&ja4_chrome()), "t13d1516h2_8daaf6152771_e5627efa2ab1");
+    }
+
+    #[test]
+    fn ja4_without_sni_alpn_or_tls13() {
+        let hello = ja3_readme(false);
+        let ja4 = ja4(&hello);
+        // TLS 1.0, SNI present, 12 ciphers, 3 extensions, no ALPN
+        assert!(ja4.starts_with("t10d1203"), "{}", ja4);
+        assert!(ja4.contains("00_"), "{}", ja4);
+
+        let bare = ClientHello::parse_body(body(&[], &[])).unwrap();
+        let ja4 = super::ja4(&bare);
+        assert!(ja4.starts_with("t12i0200"), "{}", ja4);
+        assert!(ja4.ends_with("_000000000000"), "{}", ja4);
+    }
+}
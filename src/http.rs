@@ -0,0 +1,230 @@
+// plaintext HTTP, routed by Host through the same MATCHLIST as SNI
+// only the first request of a connection gets a say, so whatever we
+// answer ourselves goes out with Connection: close
+
+use std::{convert::Infallible, path::Path, time::Duration};
+
+use http_body_util::Full;
+use hyper::{
+    Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header::{CONTENT_TYPE, HOST, LOCATION},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+use crate::dispatcher::Dispatcher;
+use crate::track::{ConnInfo, Tracked};
+
+const PEEK_SIZE: usize = 10240;
+const ACME_PREFIX: &str = "/.well-known/acme-challenge/";
+// about a second for the request head to show up in full
+const HEAD_PEEKS: usize = 40;
+const HEAD_PEEK_WAIT: Duration = Duration::from_millis(25);
+
+// does this look like the start of a request line
+pub fn looks_like_http(buf: &[u8]) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    httparse::Request::new(&mut headers).parse(buf).is_ok()
+}
+
+// the dedicated plaintext port lands here
+pub async fn handle_connection(socket: TcpStream, client: SocketAddr) {
+    let conn = Tracked::new(client);
+    dispatch(socket, &conn).await
+}
+
+pub async fn dispatch(socket: TcpStream, conn: &ConnInfo) {
+    let (host, path) = match peek_head(&socket).await {
+        Ok(Some(head)) => head,
+        Ok(None) => {
+            tracing::debug!("not an HTTP request we understand");
+            return;
+        }
+        Err(err) => {
+            tracing::debug!("couldn't peek request: {:?}", err);
+            return;
+        }
+    };
+    tracing::debug!("plaintext request for {:?} {}", host, path);
+    conn.sni.set(host.clone()).ok();
+
+    let cfg = crate::fullcfg();
+    if path.starts_with(ACME_PREFIX)
+        && let Some(http) = &cfg.http
+    {
+        if let Some(downstream) = &http.acme_downstream {
+            conn.rule.set("__acme".to_string()).ok();
+            conn.downstream.set(downstream.clone()).ok();
+            if let Err(err) = crate::proxy::tcp_proxy_addr(socket, downstream, false, conn).await {
+                tracing::debug!("acme proxy termination: {:?}", err);
+            }
+            return;
+        }
+        if http.acme_webroot.is_some() {
+            conn.rule.set("__acme".to_string()).ok();
+            if let Err(err) = serve_plain(socket).await {
+                tracing::debug!("plaintext termination: {:?}", err);
+            }
+            return;
+        }
+    }
+
+    let indicated = crate::matcher::normalize(&host);
+    let routed = crate::matchlist()
+        .lookup(&indicated, None)
+        .and_then(|matcher| {
+            matcher
+                .http_dispatcher()
+                .map(|dispatcher| (matcher.rulename().to_string(), dispatcher.clone()))
+        });
+    match routed {
+        Some((rulename, dispatcher)) => {
+            conn.rule.set(rulename).ok();
+            dispatcher.do_dispatch(socket, conn).await
+        }
+        None => {
+            if let Err(err) = serve_plain(socket).await {
+                tracing::debug!("plaintext termination: {:?}", err);
+            }
+        }
+    }
+}
+
+// Host (sans port) and path of the first request, without consuming it
+async fn peek_head(socket: &TcpStream) -> std::io::Result<Option<(String, String)>> {
+    let mut peekbuf = [0; PEEK_SIZE];
+    for _ in 0..HEAD_PEEKS {
+        let rsz = socket.peek(&mut peekbuf).await?;
+        if rsz == 0 {
+            return Ok(None);
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&peekbuf[..rsz]) {
+            Ok(httparse::Status::Complete(_)) => {
+                let host = req
+                    .headers
+                    .iter()
+                    .find(|header| header.name.eq_ignore_ascii_case("host"))
+                    .and_then(|header| std::str::from_utf8(header.value).ok())
+                    .map(strip_port)
+                    .unwrap_or_default();
+                return Ok(Some((
+                    host.to_string(),
+                    req.path.unwrap_or("/").to_string(),
+                )));
+            }
+            // peek doesn't wait for more, so we do
+            Ok(httparse::Status::Partial) if rsz < PEEK_SIZE => {
+                tokio::time::sleep(HEAD_PEEK_WAIT).await
+            }
+            _ => return Ok(None),
+        }
+    }
+    Ok(None)
+}
+
+fn strip_port(host: &str) -> &str {
+    let host = host.trim();
+    if let Some(bracketed) = host.strip_prefix('[') {
+        // [v6]:port
+        return bracketed.split(']').next().unwrap_or(bracketed);
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+// ACME challenges, redirects and 404s, one request and done
+pub async fn serve_plain(socket: TcpStream) -> hyper::Result<()> {
+    http1::Builder::new()
+        .keep_alive(false)
+        .serve_connection(TokioIo::new(socket), service_fn(answer))
+        .await
+}
+
+async fn answer(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(strip_port)
+        .or_else(|| req.uri().host())
+        .unwrap_or_default()
+        .to_string();
+    let path = req.uri().path();
+
+    if let Some(token) = path.strip_prefix(ACME_PREFIX)
+        && let Some(webroot) = crate::fullcfg()
+            .http
+            .as_ref()
+            .and_then(|http| http.acme_webroot.clone())
+    {
+        return Ok(acme_challenge(&webroot, token).await);
+    }
+
+    let indicated = crate::matcher::normalize(&host);
+    let matchers = crate::matchlist();
+    if let Some(Dispatcher::HTTPSRedirectDispatcher { https_port }) = matchers
+        .lookup(&indicated, None)
+        .and_then(|matcher| matcher.http_dispatcher())
+    {
+        // strip_port ate the brackets off a v6 literal
+        let host = if host.contains(':') {
+            format!("[{}]", host)
+        } else {
+            host
+        };
+        let location = match https_port {
+            Some(port) if *port != 443 => format!("https://{}:{}", host, port),
+            _ => format!("https://{}", host),
+        } + req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+        tracing::debug!("redirecting to {}", location);
+        if let Ok(res) = Response::builder()
+            .status(StatusCode::PERMANENT_REDIRECT)
+            .header(LOCATION, location)
+            .body(Full::default())
+        {
+            return Ok(res);
+        }
+    }
+    Ok(text(StatusCode::NOT_FOUND, "not found\n"))
+}
+
+// tokens are base64url, so anything else is somebody poking around
+async fn acme_challenge(webroot: &str, token: &str) -> Response<Full<Bytes>> {
+    if token.is_empty()
+        || !token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    {
+        return text(StatusCode::NOT_FOUND, "not found\n");
+    }
+    match tokio::fs::read(Path::new(webroot).join(token)).await {
+        Ok(body) => {
+            tracing::info!("answered acme challenge {}", token);
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/octet-stream")
+                .body(Full::new(Bytes::from(body)))
+                .unwrap()
+        }
+        Err(err) => {
+            tracing::debug!("acme challenge {}: {}", token, err);
+            text(StatusCode::NOT_FOUND, "not found\n")
+        }
+    }
+}
+
+fn text(status: StatusCode, body: &'static str) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
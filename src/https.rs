@@ -11,6 +11,10 @@ use hyper_util::rt::TokioIo;
 use std::pin::Pin;
 use tokio::{io, net::TcpStream};
 use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+
+use crate::authz::ClientAuthz;
+use crate::track::ConnInfo;
 
 #[derive(Debug, Clone)]
 pub struct WebService {
@@ -21,17 +25,32 @@ pub struct WebService {
 impl WebService {
     pub fn new(response_code: u16, response_body: String) -> Self {
         Self {
-            response_code: response_code,
+            response_code,
             response_body: Full::new(Bytes::from(response_body)),
         }
     }
+    pub fn response_code(&self) -> u16 {
+        self.response_code
+    }
     pub async fn https_serve_conn(
         &self,
         incoming: TcpStream,
         acceptor: Arc<TlsAcceptor>,
+        authz: Option<&ClientAuthz>,
+        conn: &ConnInfo,
     ) -> io::Result<()> {
+        let stream = acceptor.accept(incoming).await?;
+        if let Some(authz) = authz
+            && !authz.admits(&stream, conn)
+        {
+            authz.deny(stream).await;
+            return Ok(());
+        }
+        self.serve(stream).await
+    }
+    pub async fn serve(&self, stream: TlsStream<TcpStream>) -> io::Result<()> {
         let result = http1::Builder::new()
-            .serve_connection(TokioIo::new(acceptor.accept(incoming).await?), self)
+            .serve_connection(TokioIo::new(stream), self)
             .await;
         if result.is_ok() {
             return Ok(());
@@ -0,0 +1,309 @@
+use std::{collections::HashMap, ops::Deref};
+
+use regex::{RegexSet, RegexSetBuilder};
+use serde::{Serialize, Serializer};
+
+use crate::fingerprint::Fingerprint;
+use crate::matcher::Matcher;
+
+// MATCHLIST plus everything needed to not walk it per connection
+// exact names hash, wildcards and suffixes live in a reversed-label
+// trie, and all the regexes run as one RegexSet
+// first-match-by-file-order still holds: every structure remembers
+// rule positions and the lowest position wins
+pub struct MatchList {
+    matchers: Vec<Matcher>,
+    exact: HashMap<String, usize>,
+    labels: LabelNode,
+    regexes: RegexSet,
+    // RegexSet pattern number to rule position
+    regex_positions: Vec<usize>,
+    universal: Option<usize>,
+    // rules with fingerprint lists, which none of the above can judge;
+    // there aren't many, so they get walked
+    conditional: Vec<usize>,
+}
+
+#[derive(Default)]
+struct LabelNode {
+    children: HashMap<String, LabelNode>,
+    // first SuffixMatcher rooted at this name
+    suffix: Option<usize>,
+    // first WildcardMatcher whose "*." sits directly above this name
+    wildcard: Option<usize>,
+}
+
+impl LabelNode {
+    fn descend(&mut self, name: &str) -> &mut LabelNode {
+        name.rsplit('.').fold(self, |node, label| {
+            node.children.entry(label.to_string()).or_default()
+        })
+    }
+}
+
+impl MatchList {
+    pub fn new(matchers: Vec<Matcher>) -> Result<MatchList, regex::Error> {
+        let mut exact = HashMap::new();
+        let mut labels = LabelNode::default();
+        let mut patterns = Vec::new();
+        let mut regex_positions = Vec::new();
+        let mut universal = None;
+        let mut conditional = Vec::new();
+        for (position, matcher) in matchers.iter().enumerate() {
+            if matcher.acl().is_some() {
+                conditional.push(position);
+                continue;
+            }
+            match matcher {
+                Matcher::ExactMatcher { exact: name, .. } => {
+                    exact.entry(name.clone()).or_insert(position);
+                }
+                Matcher::RegexMatcher { regex, .. } => {
+                    patterns.push(regex.as_str().to_string());
+                    regex_positions.push(position);
+                }
+                Matcher::WildcardMatcher { wildcard, .. } => {
+                    labels
+                        .descend(wildcard.trim_start_matches('.'))
+                        .wildcard
+                        .get_or_insert(position);
+                }
+                Matcher::SuffixMatcher { suffix, .. } => {
+                    labels.descend(suffix).suffix.get_or_insert(position);
+                }
+                Matcher::UniversalMatcher { .. } => {
+                    universal.get_or_insert(position);
+                }
+            }
+        }
+        // same flags the individual regexes were built with; thousands of
+        // patterns outgrow the default lazy DFA cache and fall back to
+        // something slower than walking the list, it only grows as used
+        let regexes = RegexSetBuilder::new(patterns)
+            .case_insensitive(true)
+            .dfa_size_limit(64 << 20)
+            .build()?;
+        Ok(MatchList {
+            matchers,
+            exact,
+            labels,
+            regexes,
+            regex_positions,
+            universal,
+            conditional,
+        })
+    }
+
+    // indicated must already be normalize()d
+    pub fn lookup(&self, indicated: &str, fingerprint: Option<&Fingerprint>) -> Option<&Matcher> {
+        let mut best = self.universal;
+        earliest(&mut best, self.exact.get(indicated).copied());
+
+        let depth = indicated.split('.').count();
+        let mut node = &self.labels;
+        for (consumed, label) in indicated.rsplit('.').enumerate() {
+            let Some(child) = node.children.get(label) else {
+                break;
+            };
+            node = child;
+            earliest(&mut best, node.suffix);
+            if depth - (consumed + 1) == 1 {
+                earliest(&mut best, node.wildcard);
+            }
+        }
+
+        // only bother with the regexes if one of them could still win
+        if self
+            .regex_positions
+            .first()
+            .is_some_and(|first| best.is_none_or(|best| *first < best))
+        {
+            earliest(
+                &mut best,
+                self.regexes
+                    .matches(indicated)
+                    .iter()
+                    .next()
+                    .map(|pattern| self.regex_positions[pattern]),
+            );
+        }
+
+        if let Some(position) = self
+            .conditional
+            .iter()
+            .take_while(|position| best.is_none_or(|best| **position < best))
+            .find(|position| self.matchers[**position].admits(indicated, fingerprint))
+        {
+            best = Some(*position);
+        }
+
+        best.map(|position| &self.matchers[position])
+    }
+}
+
+fn earliest(best: &mut Option<usize>, candidate: Option<usize>) {
+    if let Some(candidate) = candidate
+        && best.is_none_or(|best| candidate < best)
+    {
+        *best = Some(candidate);
+    }
+}
+
+impl Deref for MatchList {
+    type Target = [Matcher];
+    fn deref(&self) -> &[Matcher] {
+        &self.matchers
+    }
+}
+
+impl Serialize for MatchList {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        self.matchers.serialize(ser)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::RegexBuilder;
+
+    use super::*;
+    use crate::dispatcher::Dispatcher;
+
+    fn tcp() -> Dispatcher {
+        Dispatcher::TCPDownstreamDispatcher {
+            downstreams: vec![],
+            splice: false,
+        }
+    }
+
+    fn exact(rulename: &str, exact: &str) -> Matcher {
+        Matcher::ExactMatcher {
+            rulename: rulename.to_string(),
+            dispatcher: tcp(),
+            http_dispatcher: None,
+            acl: None,
+            exact: exact.to_string(),
+        }
+    }
+
+    fn suffix(rulename: &str, suffix: &str) -> Matcher {
+        Matcher::SuffixMatcher {
+            rulename: rulename.to_string(),
+            dispatcher: tcp(),
+            http_dispatcher: None,
+            acl: None,
+            suffix: suffix.to_string(),
+        }
+    }
+
+    fn wildcard(rulename: &str, wildcard: &str) -> Matcher {
+        Matcher::WildcardMatcher {
+            rulename: rulename.to_string(),
+            dispatcher: tcp(),
+            http_dispatcher: None,
+            acl: None,
+            wildcard: wildcard.to_string(),
+        }
+    }
+
+    fn regex(rulename: &str, pattern: &str) -> Matcher {
+        Matcher::RegexMatcher {
+            rulename: rulename.to_string(),
+            dispatcher: tcp(),
+            http_dispatcher: None,
+            acl: None,
+            regex: RegexBuilder::new(pattern)
+                .case_insensitive(true)
+                .build()
+                .unwrap(),
+        }
+    }
+
+    fn universal(rulename: &str) -> Matcher {
+        Matcher::UniversalMatcher {
+            rulename: rulename.to_string(),
+            dispatcher: tcp(),
+            http_dispatcher: None,
+            acl: None,
+        }
+    }
+
+    fn winner<'a>(list: &'a MatchList, indicated: &str) -> Option<&'a str> {
+        list.lookup(indicated, None)
+            .map(|matcher| matcher.rulename())
+    }
+
+    #[test]
+    fn exact_wins_only_when_it_comes_first() {
+        let list = MatchList::new(vec![
+            exact("exact", "www.example.com"),
+            suffix("suffix", "example.com"),
+        ])
+        .unwrap();
+        assert_eq!(winner(&list, "www.example.com"), Some("exact"));
+        assert_eq!(winner(&list, "api.example.com"), Some("suffix"));
+
+        let list = MatchList::new(vec![
+            suffix("suffix", "example.com"),
+            exact("exact", "www.example.com"),
+        ])
+        .unwrap();
+        assert_eq!(winner(&list, "www.example.com"), Some("suffix"));
+    }
+
+    #[test]
+    fn broader_rules_before_exact_win() {
+        for broader in [
+            wildcard("broader", ".example.com"),
+            regex("broader", r"^www\."),
+            universal("broader"),
+        ] {
+            let list = MatchList::new(vec![broader, exact("exact", "www.example.com")]).unwrap();
+            assert_eq!(winner(&list, "www.example.com"), Some("broader"));
+        }
+        for broader in [
+            wildcard("broader", ".example.com"),
+            regex("broader", r"^www\."),
+            universal("broader"),
+        ] {
+            let list = MatchList::new(vec![exact("exact", "www.example.com"), broader]).unwrap();
+            assert_eq!(winner(&list, "www.example.com"), Some("exact"));
+        }
+    }
+
+    #[test]
+    fn agrees_with_walking_the_list() {
+        let list = MatchList::new(vec![
+            regex("late-regex", r"^db\d+\."),
+            wildcard("wildcard", ".example.com"),
+            exact("exact", "www.example.com"),
+            suffix("suffix", "example.com"),
+            suffix("deeper", "a.b.example.com"),
+            exact("apex", "example.net"),
+            regex("net", r"\.net$"),
+            universal("universal"),
+        ])
+        .unwrap();
+        for indicated in [
+            "www.example.com",
+            "example.com",
+            "a.b.example.com",
+            "x.a.b.example.com",
+            "db1.example.com",
+            "db1.example.org",
+            "example.net",
+            "www.example.net",
+            "elsewhere.org",
+            "",
+        ] {
+            assert_eq!(
+                winner(&list, indicated),
+                list.iter()
+                    .find(|matcher| matcher.admits(indicated, None))
+                    .map(|matcher| matcher.rulename()),
+                "{}",
+                indicated
+            );
+        }
+    }
+}
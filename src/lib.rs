@@ -1,13 +1,12 @@
 use std::{
     collections::HashMap,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{
-        Arc, LazyLock,
+        Arc, LazyLock, RwLock,
         atomic::{AtomicBool, AtomicU32},
     },
 };
 
-use config::Config;
 use structopt::StructOpt;
 use tokio::{
     sync::{
@@ -18,58 +17,235 @@ use tokio::{
 };
 use tokio_rustls::TlsAcceptor;
 
-use crate::{conf::Configuration, matcher::Matcher};
+use crate::{
+    conf::{Configuration, Diagnostics},
+    ech::EchKeys,
+    expiry::Watched,
+    index::MatchList,
+    matcher::Matcher,
+};
 
+pub mod admin;
+pub mod alert;
+pub mod authz;
+pub mod clienthello;
+pub mod cmd;
 pub mod conf;
 pub mod conn;
+pub mod crl;
 pub mod dispatcher;
+pub mod ech;
+pub mod expiry;
+pub mod fingerprint;
+pub mod http;
 pub mod https;
+pub mod index;
 pub mod matcher;
+pub mod ocsp;
+pub mod provider;
 pub mod proxy;
+pub mod sniff;
+pub mod sockopt;
+#[cfg(target_os = "linux")]
+pub mod splice;
+pub mod starttls;
 pub mod tasks;
+pub mod tickets;
 pub mod tls;
+pub mod track;
 
 pub static CONNS_VENDED: AtomicU32 = AtomicU32::new(0);
 pub static CONNS_OKAY: AtomicU32 = AtomicU32::new(0);
 pub static CONNS_PANICED: AtomicU32 = AtomicU32::new(0);
 pub static CONNS_ENDED: AtomicBool = AtomicBool::new(false);
 pub static SCONNS: LazyLock<Mutex<JoinSet<()>>> = LazyLock::new(|| Mutex::new(JoinSet::new()));
-pub static FULLCFG: LazyLock<Configuration> = LazyLock::new(|| {
-    Config::builder()
-        .add_source(config::File::with_name(
-            CliOptions::from_args()
-                .conf
-                .to_str()
-                .expect("invalid pathname"),
-        ))
-        .add_source(config::Environment::with_prefix("LURKR"))
-        .build()
+// TLS config name to its acceptor
+pub type TlsMap = HashMap<String, Arc<TlsAcceptor>>;
+
+// these get swapped wholesale on (re)load; grab an Arc and go
+pub static FULLCFG: RwLock<Option<Arc<Configuration>>> = RwLock::new(None);
+pub static TLSMAP: RwLock<Option<Arc<TlsMap>>> = RwLock::new(None);
+pub static MATCHLIST: RwLock<Option<Arc<MatchList>>> = RwLock::new(None);
+
+// only there when [ech] is
+pub static ECH: RwLock<Option<Arc<EchKeys>>> = RwLock::new(None);
+
+pub fn fullcfg() -> Arc<Configuration> {
+    FULLCFG
+        .read()
         .unwrap()
-        .try_deserialize()
-        .expect("could not deserialize configuration")
-});
+        .clone()
+        .expect("configuration not loaded")
+}
 
-pub static TLSMAP: LazyLock<HashMap<String, Arc<TlsAcceptor>>> = LazyLock::new(|| {
-    crate::tls::acceptors_from_configuration().expect("couldn't create TLS acceptors")
-});
-pub static MATCHLIST: LazyLock<Vec<Matcher>> = LazyLock::new(|| Matcher::from_configuration());
+pub fn tlsmap() -> Arc<TlsMap> {
+    TLSMAP
+        .read()
+        .unwrap()
+        .clone()
+        .expect("configuration not loaded")
+}
+
+pub fn matchlist() -> Arc<MatchList> {
+    MATCHLIST
+        .read()
+        .unwrap()
+        .clone()
+        .expect("configuration not loaded")
+}
+
+// a configuration that checked out, but isn't live yet
+pub struct Assembled {
+    pub cfg: Configuration,
+    pub tlses: TlsMap,
+    pub matchers: MatchList,
+    pub ech: Option<EchKeys>,
+    // every loaded cert's notAfter, for expiry::install
+    pub watched: Vec<Watched>,
+}
+
+// build everything a config file describes without touching live state
+// any error at all fails the lot, warnings ride along with success
+pub fn assemble(path: &Path) -> Result<(Assembled, Diagnostics), Diagnostics> {
+    let mut diag = Diagnostics::default();
+    let mut cfg = match Configuration::load(path) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            diag.error(format!("{}: {:#}", path.display(), err));
+            return Err(diag);
+        }
+    };
+    cfg.load_includes(path, &mut diag);
+    crate::provider::validate(&cfg, &mut diag);
+    crate::sockopt::validate(&cfg, &mut diag);
+    let (tlses, watched) = crate::tls::acceptors_from_configuration(&cfg, &mut diag);
+    crate::expiry::assess(&cfg, &watched, &mut diag);
+    let matchers = match MatchList::new(Matcher::from_configuration(&cfg, &tlses, &mut diag)) {
+        Ok(matchers) => matchers,
+        Err(err) => {
+            diag.error(format!("couldn't index mappings: {}", err));
+            return Err(diag);
+        }
+    };
+    let ech = cfg.ech.as_ref().and_then(|ech| {
+        if let Some(fallback) = &ech.fallback
+            && !matchers
+                .iter()
+                .any(|matcher| matcher.rulename() == fallback)
+        {
+            diag.error(format!("ech: fallback mapping {} not found", fallback));
+        }
+        EchKeys::from_path(&ech.key_path)
+            .map_err(|err| diag.error(format!("ech: {:#}", err)))
+            .ok()
+    });
+    if !diag.is_ok() {
+        return Err(diag);
+    }
+    Ok((
+        Assembled {
+            cfg,
+            tlses,
+            matchers,
+            ech,
+            watched,
+        },
+        diag,
+    ))
+}
+
+// (re)read the config file and swap in fresh TLS and matchers
+// a bad config leaves whatever was live alone
+// connections already dispatched keep whatever they had
+// the listener itself isn't rebound, so addr/port changes need a restart
+pub fn load() -> anyhow::Result<()> {
+    let (mut assembled, diag) = assemble(&CLI_OPTIONS.conf)?;
+    for warning in diag.warnings.iter() {
+        tracing::warn!("{}", warning);
+    }
+    // route providers' mappings survive a reload
+    let provided = crate::provider::PROVIDED.lock().unwrap();
+    if !provided.is_empty() {
+        let (matchers, _) =
+            crate::provider::build_matchlist(&assembled.cfg, &provided, &assembled.tlses)?;
+        assembled.matchers = matchers;
+    }
+    crate::expiry::install(&assembled.cfg, assembled.watched);
+    *FULLCFG.write().unwrap() = Some(Arc::new(assembled.cfg));
+    *TLSMAP.write().unwrap() = Some(Arc::new(assembled.tlses));
+    *MATCHLIST.write().unwrap() = Some(Arc::new(assembled.matchers));
+    *ECH.write().unwrap() = assembled.ech.map(Arc::new);
+    drop(provided);
+    tracing::info!("configuration loaded from {}", CLI_OPTIONS.conf.display());
+    Ok(())
+}
+
+// tests that make a config live take turns at it
+#[cfg(test)]
+pub(crate) static TEST_GLOBALS: Mutex<()> = Mutex::const_new(());
+
+// a config made live the way load() would, without the command line;
+// hold TEST_GLOBALS around it
+#[cfg(test)]
+pub(crate) fn install_for_test(dir: &Path, config: &str) {
+    std::fs::create_dir_all(dir).unwrap();
+    let conf = dir.join("lurkr.toml");
+    std::fs::write(&conf, config).unwrap();
+    let (assembled, _) = assemble(&conf).unwrap_or_else(|diag| panic!("{}", diag));
+    *FULLCFG.write().unwrap() = Some(Arc::new(assembled.cfg));
+    *TLSMAP.write().unwrap() = Some(Arc::new(assembled.tlses));
+    *MATCHLIST.write().unwrap() = Some(Arc::new(assembled.matchers));
+    *ECH.write().unwrap() = assembled.ech.map(Arc::new);
+}
 
 #[derive(Debug, StructOpt)]
 pub struct CliOptions {
     /// Enable debug-level logging
-    #[structopt(short, long)]
+    #[structopt(short, long, global = true)]
     pub debug: bool,
 
     /// Path to listener configuration TOML
-    #[structopt(short, long, parse(from_os_str))]
+    #[structopt(
+        short,
+        long,
+        parse(from_os_str),
+        global = true,
+        default_value = "lurkr.toml"
+    )]
     pub conf: PathBuf,
 
     /// Grace period seconds before soft termination and then hard
     #[structopt(default_value = "1", short, long)]
     pub grace_period: u64,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Show which mapping an SNI would hit, and why
+    Route {
+        /// Server name to route, "" for a ClientHello without SNI
+        sni: String,
+        /// JA3 hash of the pretend client, for rules that list some
+        #[structopt(long)]
+        ja3: Option<String>,
+        /// JA4 fingerprint of the pretend client
+        #[structopt(long)]
+        ja4: Option<String>,
+    },
+    /// Validate the configuration and report every problem found
+    Check,
+    /// Make an ECH key and config: the file [ech] key_path wants goes
+    /// to stdout, the ech= value for a DNS HTTPS record to stderr
+    EchKeygen {
+        /// The name outer ClientHellos will carry, served by this lurkr
+        public_name: String,
+    },
 }
 
-pub static CLI_OPTIONS: LazyLock<CliOptions> = LazyLock::new(|| CliOptions::from_args());
+pub static CLI_OPTIONS: LazyLock<CliOptions> = LazyLock::new(CliOptions::from_args);
 
 pub static LISTENER_STOP: LazyLock<(Sender<()>, Receiver<()>)> =
     LazyLock::new(|| watch::channel(()));
@@ -1,76 +1,432 @@
-use regex::Regex;
-use rustls::AlertDescription;
-use rustls::internal::msgs::enums::AlertLevel;
+use regex::{Regex, RegexBuilder};
+use serde::{Serialize, Serializer, ser::SerializeStruct};
 
+use crate::TlsMap;
+use std::collections::HashSet;
+
+use crate::conf::{Configuration, Diagnostics, MappingEntry};
 use crate::dispatcher::Dispatcher;
+use crate::fingerprint::{Fingerprint, FingerprintAcl};
 
 #[derive(Debug)]
 pub enum Matcher {
     ExactMatcher {
         rulename: String,
         dispatcher: Dispatcher,
+        // what plaintext HTTP for this name gets, if anything
+        http_dispatcher: Option<Dispatcher>,
+        // and only for these TLS fingerprints, if any
+        acl: Option<FingerprintAcl>,
         // determinant for this type
         exact: String,
     },
     RegexMatcher {
         rulename: String,
         dispatcher: Dispatcher,
+        http_dispatcher: Option<Dispatcher>,
+        acl: Option<FingerprintAcl>,
         // determinant for this type
         regex: Regex,
     },
+    // DNS-style "*.example.com": exactly one label, never the apex
+    WildcardMatcher {
+        rulename: String,
+        dispatcher: Dispatcher,
+        http_dispatcher: Option<Dispatcher>,
+        acl: Option<FingerprintAcl>,
+        // stored without the "*", so ".example.com"
+        wildcard: String,
+    },
+    // "example.com" and everything underneath it, on label boundaries
+    SuffixMatcher {
+        rulename: String,
+        dispatcher: Dispatcher,
+        http_dispatcher: Option<Dispatcher>,
+        acl: Option<FingerprintAcl>,
+        suffix: String,
+    },
     UniversalMatcher {
         rulename: String,
         dispatcher: Dispatcher,
+        http_dispatcher: Option<Dispatcher>,
+        acl: Option<FingerprintAcl>,
         // "isn't anything else" determinant
     },
 }
 
+// SNI compares case-insensitively (RFC 6066 defers to DNS), and a
+// trailing dot is the same name; everything gets matched in this form
+pub fn normalize(name: &str) -> String {
+    name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase()
+}
+
+impl Serialize for Matcher {
+    fn serialize<S: Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        let mut st = ser.serialize_struct("Matcher", 7)?;
+        st.serialize_field("rulename", self.rulename())?;
+        match self {
+            Matcher::ExactMatcher { exact, .. } => {
+                st.serialize_field("kind", "ExactMatcher")?;
+                st.serialize_field("exact", exact)?;
+            }
+            Matcher::RegexMatcher { regex, .. } => {
+                st.serialize_field("kind", "RegexMatcher")?;
+                st.serialize_field("regex", regex.as_str())?;
+            }
+            Matcher::WildcardMatcher { wildcard, .. } => {
+                st.serialize_field("kind", "WildcardMatcher")?;
+                st.serialize_field("wildcard", &format!("*{}", wildcard))?;
+            }
+            Matcher::SuffixMatcher { suffix, .. } => {
+                st.serialize_field("kind", "SuffixMatcher")?;
+                st.serialize_field("suffix", suffix)?;
+            }
+            Matcher::UniversalMatcher { .. } => {
+                st.serialize_field("kind", "UniversalMatcher")?;
+                st.skip_field("determinant")?;
+            }
+        }
+        st.serialize_field("dispatcher", self.dispatcher())?;
+        st.serialize_field("http_dispatcher", &self.http_dispatcher())?;
+        match self.acl() {
+            Some(acl) => {
+                st.serialize_field("ja3", &acl.ja3)?;
+                st.serialize_field("ja4", &acl.ja4)?;
+            }
+            None => {
+                st.skip_field("ja3")?;
+                st.skip_field("ja4")?;
+            }
+        }
+        st.end()
+    }
+}
+
 impl Matcher {
+    pub fn rulename(&self) -> &str {
+        match self {
+            Matcher::ExactMatcher { rulename, .. }
+            | Matcher::RegexMatcher { rulename, .. }
+            | Matcher::WildcardMatcher { rulename, .. }
+            | Matcher::SuffixMatcher { rulename, .. }
+            | Matcher::UniversalMatcher { rulename, .. } => rulename,
+        }
+    }
+
+    pub fn dispatcher(&self) -> &Dispatcher {
+        match self {
+            Matcher::ExactMatcher { dispatcher, .. }
+            | Matcher::RegexMatcher { dispatcher, .. }
+            | Matcher::WildcardMatcher { dispatcher, .. }
+            | Matcher::SuffixMatcher { dispatcher, .. }
+            | Matcher::UniversalMatcher { dispatcher, .. } => dispatcher,
+        }
+    }
+
+    pub fn http_dispatcher(&self) -> Option<&Dispatcher> {
+        match self {
+            Matcher::ExactMatcher {
+                http_dispatcher, ..
+            }
+            | Matcher::RegexMatcher {
+                http_dispatcher, ..
+            }
+            | Matcher::WildcardMatcher {
+                http_dispatcher, ..
+            }
+            | Matcher::SuffixMatcher {
+                http_dispatcher, ..
+            }
+            | Matcher::UniversalMatcher {
+                http_dispatcher, ..
+            } => http_dispatcher.as_ref(),
+        }
+    }
+
+    pub fn acl(&self) -> Option<&FingerprintAcl> {
+        match self {
+            Matcher::ExactMatcher { acl, .. }
+            | Matcher::RegexMatcher { acl, .. }
+            | Matcher::WildcardMatcher { acl, .. }
+            | Matcher::SuffixMatcher { acl, .. }
+            | Matcher::UniversalMatcher { acl, .. } => acl.as_ref(),
+        }
+    }
+
+    // name matches and the fingerprint, if the rule cares, does too
+    pub fn admits(&self, indicated: &str, fingerprint: Option<&Fingerprint>) -> bool {
+        self.matches(indicated)
+            && self
+                .acl()
+                .is_none_or(|acl| fingerprint.is_some_and(|fingerprint| acl.permits(fingerprint)))
+    }
+
+    // indicated must already be normalize()d
+    pub fn matches(&self, indicated: &str) -> bool {
+        match self {
+            Matcher::ExactMatcher { exact, .. } => exact.as_str() == indicated,
+            Matcher::RegexMatcher { regex, .. } => regex.is_match(indicated),
+            Matcher::WildcardMatcher { wildcard, .. } => indicated
+                .strip_suffix(wildcard.as_str())
+                .is_some_and(|label| !label.is_empty() && !label.contains('.')),
+            Matcher::SuffixMatcher { suffix, .. } => {
+                indicated == suffix
+                    || indicated
+                        .strip_suffix(suffix.as_str())
+                        .is_some_and(|sub| sub.ends_with('.'))
+            }
+            Matcher::UniversalMatcher { .. } => true,
+        }
+    }
+
+    // human-readable determinant, for explaining ourselves
+    pub fn describe(&self) -> String {
+        let name = match self {
+            Matcher::ExactMatcher { exact, .. } => format!("exact {:?}", exact),
+            Matcher::RegexMatcher { regex, .. } => format!("regex {:?}", regex.as_str()),
+            Matcher::WildcardMatcher { wildcard, .. } => format!("wildcard \"*{}\"", wildcard),
+            Matcher::SuffixMatcher { suffix, .. } => format!("suffix {:?}", suffix),
+            Matcher::UniversalMatcher { .. } => "universal".to_string(),
+        };
+        match self.acl() {
+            Some(acl) => format!("{} +{} ja3 +{} ja4", name, acl.ja3.len(), acl.ja4.len()),
+            None => name,
+        }
+    }
+
     // Matchers define the SNI-to-execution mapping
-    pub fn from_configuration() -> Vec<Matcher> {
+    // broken mappings are reported by name and left out
+    pub fn from_configuration(
+        cfg: &Configuration,
+        tlsmap: &TlsMap,
+        diag: &mut Diagnostics,
+    ) -> Vec<Matcher> {
+        Matcher::from_mappings(cfg, cfg.mapping.iter(), tlsmap, diag)
+    }
+
+    // same, for any mappings at all, under cfg's tls and matching settings
+    pub fn from_mappings<'a>(
+        cfg: &Configuration,
+        mappings: impl Iterator<Item = (&'a String, &'a MappingEntry)>,
+        tlsmap: &TlsMap,
+        diag: &mut Diagnostics,
+    ) -> Vec<Matcher> {
         let mut matchers = Vec::<Matcher>::new();
+        let mut seen = HashSet::<&String>::new();
+        let anchor_default = cfg
+            .matching
+            .as_ref()
+            .and_then(|matching| matching.anchored_regex)
+            .unwrap_or(false);
         // TODO: ordering? weights? preserve order feature in config-rs?
-        for (mapname, mapspec) in crate::FULLCFG.mapping.iter() {
+        for (mapname, mapspec) in mappings {
             tracing::debug!("assembling mapping {}", mapname);
-            if let Some(dispatcher) = Dispatcher::from_mappingentry(&mapspec) {
-                if mapspec.exact.is_some() && mapspec.regex.is_some() {
-                    panic!(
-                        "mapping entry {} cannot have both exact and regex matching",
-                        mapname
-                    );
-                } else if mapspec.exact.is_none() && mapspec.regex.is_none() {
-                    matchers.push(Matcher::UniversalMatcher {
-                        rulename: mapname.clone(),
-                        dispatcher: dispatcher,
-                    });
-                } else if let Some(direct) = &mapspec.exact {
-                    matchers.push(Matcher::ExactMatcher {
-                        rulename: mapname.clone(),
-                        exact: direct.clone(),
-                        dispatcher: dispatcher,
-                    });
-                } else if let Some(regex) = &mapspec.regex {
-                    matchers.push(Matcher::RegexMatcher {
-                        rulename: mapname.clone(),
-                        regex: Regex::new(regex.as_str()).expect(
-                            format!("faulty regex {} in mapping {}", regex, mapname).as_str(),
-                        ),
-                        dispatcher: dispatcher,
-                    })
+            if !seen.insert(mapname) {
+                diag.error(format!(
+                    "mapping {}: name is already taken",
+                    mapspec.whence(mapname)
+                ));
+                continue;
+            }
+            let dispatcher = match Dispatcher::from_mappingentry(mapspec, tlsmap) {
+                Ok(dispatcher) => Some(dispatcher),
+                Err(_)
+                    if mapspec.tls.as_ref().is_some_and(|tlsname| {
+                        !tlsmap.contains_key(tlsname)
+                            && cfg
+                                .tls
+                                .as_ref()
+                                .is_some_and(|tlses| tlses.contains_key(tlsname))
+                    }) =>
+                {
+                    // the TLS config itself is broken and already said so
+                    None
+                }
+                Err(err) => {
+                    diag.error(format!("mapping {}: {:#}", mapspec.whence(mapname), err));
+                    None
+                }
+            };
+            let asks_for_certs = mapspec
+                .tls
+                .as_ref()
+                .and_then(|tlsname| cfg.tls.as_ref()?.get(tlsname))
+                .is_some_and(|tlsspec| {
+                    crate::tls::client_bundle_source(tlsspec).is_ok_and(|source| source.is_some())
+                });
+            if mapspec.client_allow.is_some() && !asks_for_certs {
+                diag.error(format!(
+                    "mapping {}: client_allow needs a tls config with a client_certbundle",
+                    mapspec.whence(mapname)
+                ));
+            }
+            let determinants = [
+                mapspec.exact.is_some(),
+                mapspec.regex.is_some(),
+                mapspec.wildcard.is_some(),
+                mapspec.suffix.is_some(),
+            ];
+            if determinants.iter().filter(|present| **present).count() > 1 {
+                diag.error(format!(
+                    "mapping {}: only one of exact, regex, wildcard or suffix matching",
+                    mapspec.whence(mapname)
+                ));
+                continue;
+            }
+            let regex = match &mapspec.regex {
+                Some(pattern) => {
+                    let pattern = if mapspec.anchored.unwrap_or(anchor_default) {
+                        format!("^(?:{})$", pattern)
+                    } else {
+                        pattern.clone()
+                    };
+                    match RegexBuilder::new(&pattern).case_insensitive(true).build() {
+                        Ok(regex) => Some(regex),
+                        Err(err) => {
+                            diag.error(format!(
+                                "mapping {}: faulty regex: {}",
+                                mapspec.whence(mapname),
+                                err
+                            ));
+                            continue;
+                        }
+                    }
+                }
+                None => None,
+            };
+            let wildcard = match &mapspec.wildcard {
+                Some(wildcard) => match normalize(wildcard).strip_prefix('*') {
+                    Some(rest)
+                        if rest.starts_with('.') && rest.len() > 1 && !rest.contains('*') =>
+                    {
+                        Some(rest.to_string())
+                    }
+                    _ => {
+                        diag.error(format!(
+                            "mapping {}: wildcard {:?} must look like \"*.example.com\"",
+                            mapspec.whence(mapname),
+                            wildcard
+                        ));
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let suffix = match &mapspec.suffix {
+                Some(suffix) => match normalize(suffix.strip_prefix('.').unwrap_or(suffix)) {
+                    // "" and "." would otherwise be a suffix of nothing at all
+                    rest if !rest.is_empty() && !rest.split('.').any(str::is_empty) => Some(rest),
+                    _ => {
+                        diag.error(format!(
+                            "mapping {}: suffix {:?} must look like \"example.com\"",
+                            mapspec.whence(mapname),
+                            suffix
+                        ));
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let http_dispatcher = match Dispatcher::http_from_mappingentry(mapspec, cfg) {
+                Ok(http_dispatcher) => http_dispatcher,
+                Err(err) => {
+                    diag.error(format!("mapping {}: {:#}", mapspec.whence(mapname), err));
+                    continue;
                 }
+            };
+            let Some(dispatcher) = dispatcher else {
+                continue;
+            };
+            let exact = mapspec.exact.as_deref().map(normalize);
+            let acl = if mapspec.ja3.is_some() || mapspec.ja4.is_some() {
+                Some(FingerprintAcl {
+                    ja3: mapspec
+                        .ja3
+                        .iter()
+                        .flatten()
+                        .map(|ja3| ja3.to_ascii_lowercase())
+                        .collect(),
+                    ja4: mapspec
+                        .ja4
+                        .iter()
+                        .flatten()
+                        .map(|ja4| ja4.to_ascii_lowercase())
+                        .collect(),
+                })
             } else {
-                panic!("mapping entry {} is not dispatchable", mapname);
+                None
+            };
+            if let Some(shadow) = matchers
+                .iter()
+                .filter(|earlier| earlier.acl().is_none())
+                .find(|earlier| match earlier {
+                    Matcher::UniversalMatcher { .. } => true,
+                    _ => exact.as_deref().is_some_and(|exact| earlier.matches(exact)),
+                })
+            {
+                diag.warn(format!(
+                    "mapping {}: unreachable, {} mapping {} always matches first",
+                    mapspec.whence(mapname),
+                    shadow.describe(),
+                    shadow.rulename()
+                ));
             }
+            let rulename = mapname.clone();
+            matchers.push(if let Some(exact) = exact {
+                Matcher::ExactMatcher {
+                    rulename,
+                    exact,
+                    dispatcher,
+                    http_dispatcher,
+                    acl,
+                }
+            } else if let Some(regex) = regex {
+                Matcher::RegexMatcher {
+                    rulename,
+                    regex,
+                    dispatcher,
+                    http_dispatcher,
+                    acl,
+                }
+            } else if let Some(wildcard) = wildcard {
+                Matcher::WildcardMatcher {
+                    rulename,
+                    wildcard,
+                    dispatcher,
+                    http_dispatcher,
+                    acl,
+                }
+            } else if let Some(suffix) = suffix {
+                Matcher::SuffixMatcher {
+                    rulename,
+                    suffix,
+                    dispatcher,
+                    http_dispatcher,
+                    acl,
+                }
+            } else {
+                Matcher::UniversalMatcher {
+                    rulename,
+                    dispatcher,
+                    http_dispatcher,
+                    acl,
+                }
+            });
         }
-        // we give you one free TLS unrecognized-name
-        // dispatching UniversalMatcher at the end
+        // we give you one free TLS unrecognized-name (or whatever
+        // [unmatched] says) dispatching UniversalMatcher at the end
+        let dispatcher = match Dispatcher::unmatched(cfg) {
+            Ok(dispatcher) => dispatcher,
+            Err(err) => {
+                diag.error(format!("unmatched: {:#}", err));
+                return matchers;
+            }
+        };
         matchers.push(Matcher::UniversalMatcher {
             rulename: "__default".to_string(),
-            dispatcher: Dispatcher::TLSAlertDispatcher {
-                alert_level: AlertLevel::Fatal,
-                // it's a "z" in the standard #gotem
-                alert_description: AlertDescription::UnrecognisedName,
-            },
+            dispatcher,
+            http_dispatcher: None,
+            acl: None,
         });
         matchers
     }
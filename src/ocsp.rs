@@ -0,0 +1,575 @@
+// OCSP stapling for terminated TLS: a DER response from a file, or asked
+// of the cert's responder, hung on the CertifiedKey and kept fresh
+//
+// no signature checking here, that's the client's job; we only make sure
+// it's a successful response about our certificate before stapling it
+
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Error, anyhow, bail};
+use aws_lc_rs::digest::{SHA1_FOR_LEGACY_USE_ONLY, digest};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Request, Uri};
+use hyper_util::rt::TokioIo;
+use rustls::pki_types::CertificateDer;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use tokio::net::TcpStream;
+use x509_parser::extensions::{GeneralName, ParsedExtension};
+use x509_parser::oid_registry::OID_PKIX_ACCESS_DESCRIPTOR_OCSP;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::conf::TlsConfigEntry;
+
+pub const DEFAULT_REFRESH_SECS: u64 = 60 * 60;
+// don't hammer a responder that's having a bad day
+const RETRY_SECS: u64 = 60;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// a DER OCSPResponse and its nextUpdate, unix seconds
+pub type Staple = (Vec<u8>, Option<u64>);
+
+// where the staple comes from
+#[derive(Debug, Clone)]
+pub enum OcspSource {
+    File { path: String },
+    Responder { url: String, request: Vec<u8> },
+}
+
+#[derive(Debug, Clone)]
+pub struct OcspSpec {
+    pub source: OcspSource,
+    serial: Vec<u8>,
+    refresh_secs: u64,
+}
+
+impl OcspSpec {
+    // None when the tls config doesn't ask for stapling
+    pub fn from_tlsspec(
+        tlsspec: &TlsConfigEntry,
+        certs: &[CertificateDer<'_>],
+    ) -> Result<Option<OcspSpec>, Error> {
+        let fetch = tlsspec.ocsp_fetch == Some(true) || tlsspec.ocsp_responder.is_some();
+        if tlsspec.ocsp_path.is_none() && !fetch {
+            return Ok(None);
+        }
+        if tlsspec.ocsp_path.is_some() && fetch {
+            bail!("ocsp_path and fetching from a responder don't mix, pick one");
+        }
+        let (_, leaf) = X509Certificate::from_der(&certs[0])
+            .map_err(|err| anyhow!("couldn't parse certificate for ocsp: {}", err))?;
+        let serial = leaf.raw_serial().to_vec();
+
+        let source = if let Some(path) = &tlsspec.ocsp_path {
+            OcspSource::File { path: path.clone() }
+        } else {
+            // the issuer has to be in the chain: its name and key are in the request
+            let Some(issuer) = certs.get(1) else {
+                bail!("ocsp fetching needs the issuer certificate after the leaf in certs");
+            };
+            let (_, issuer) = X509Certificate::from_der(issuer)
+                .map_err(|err| anyhow!("couldn't parse issuer certificate: {}", err))?;
+            let url = match &tlsspec.ocsp_responder {
+                Some(url) => url.clone(),
+                None => responder_url(&leaf)
+                    .ok_or_else(|| anyhow!("certificate names no ocsp responder"))?,
+            };
+            let uri: Uri = url.parse()?;
+            if uri.scheme_str() != Some("http") {
+                bail!("ocsp responder {} isn't http://", url);
+            }
+            OcspSource::Responder {
+                url,
+                request: request(&leaf, &issuer),
+            }
+        };
+        Ok(Some(OcspSpec {
+            source,
+            serial,
+            refresh_secs: tlsspec.ocsp_refresh_secs.unwrap_or(DEFAULT_REFRESH_SECS),
+        }))
+    }
+
+    // a staple that checks out, and when it stops being current
+    pub async fn obtain(&self) -> Result<Staple, Error> {
+        let response = match &self.source {
+            OcspSource::File { path } => tokio::fs::read(path)
+                .await
+                .map_err(|err| anyhow!("couldn't read {}: {}", path, err))?,
+            OcspSource::Responder { url, request } => {
+                tokio::time::timeout(FETCH_TIMEOUT, http_post(&url.parse()?, request))
+                    .await
+                    .map_err(|_| anyhow!("{} took too long", url))??
+            }
+        };
+        let next_update = check_response(&response, &self.serial)?;
+        Ok((response, next_update))
+    }
+
+    // file staples only: read it right now, so a bad path is a config error
+    pub fn obtain_now(&self) -> Result<Option<Staple>, Error> {
+        let OcspSource::File { path } = &self.source else {
+            return Ok(None);
+        };
+        let response =
+            std::fs::read(path).map_err(|err| anyhow!("couldn't read {}: {}", path, err))?;
+        let next_update = check_response(&response, &self.serial)?;
+        Ok(Some((response, next_update)))
+    }
+
+    // every refresh_secs, sooner if the response is over halfway to stale
+    fn refresh_in(&self, next_update: Option<u64>) -> u64 {
+        refresh_in(self.refresh_secs, next_update)
+    }
+
+    fn origin(&self) -> &str {
+        match &self.source {
+            OcspSource::File { path } => path,
+            OcspSource::Responder { url, .. } => url,
+        }
+    }
+}
+
+fn responder_url(leaf: &X509Certificate<'_>) -> Option<String> {
+    leaf.iter_extensions()
+        .find_map(|ext| match ext.parsed_extension() {
+            ParsedExtension::AuthorityInfoAccess(aia) => Some(aia),
+            _ => None,
+        })?
+        .accessdescs
+        .iter()
+        .filter(|desc| desc.access_method == OID_PKIX_ACCESS_DESCRIPTOR_OCSP)
+        .find_map(|desc| match desc.access_location {
+            GeneralName::URI(uri) => Some(uri.to_string()),
+            _ => None,
+        })
+}
+
+// the cert rustls hands out, swapped whenever a fresh staple shows up
+#[derive(Debug)]
+pub struct StapledCert {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl StapledCert {
+    pub fn new(certified: CertifiedKey) -> StapledCert {
+        StapledCert {
+            current: RwLock::new(Arc::new(certified)),
+        }
+    }
+
+    fn staple(&self, response: Option<Vec<u8>>) {
+        let mut current = self.current.write().unwrap();
+        let mut certified = CertifiedKey::clone(&current);
+        certified.ocsp = response;
+        *current = Arc::new(certified);
+    }
+}
+
+impl ResolvesServerCert for StapledCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+// runs until the acceptor it staples for is gone (reloaded away)
+// next_update is that of whatever's stapled already, if anything is
+pub async fn keep_stapled(stapled: Weak<StapledCert>, spec: OcspSpec, next_update: Option<u64>) {
+    let mut stale_at = next_update;
+    let mut wait = match spec.source {
+        OcspSource::File { .. } => spec.refresh_in(next_update),
+        OcspSource::Responder { .. } => 0,
+    };
+    loop {
+        tokio::time::sleep(Duration::from_secs(wait)).await;
+        let obtained = spec.obtain().await;
+        let Some(stapled) = stapled.upgrade() else {
+            return;
+        };
+        wait = match obtained {
+            Ok((response, next_update)) => {
+                log::debug!("stapling {} byte ocsp response", response.len());
+                stapled.staple(Some(response));
+                stale_at = next_update;
+                spec.refresh_in(next_update)
+            }
+            Err(err) => {
+                log::warn!("ocsp {}: {:#}", spec.origin(), err);
+                // a stale staple is worse than none
+                if stale_at.is_some_and(|stale_at| stale_at <= unix_now()) {
+                    log::warn!("ocsp staple went stale, no longer stapling");
+                    stapled.staple(None);
+                    stale_at = None;
+                }
+                RETRY_SECS.min(spec.refresh_secs)
+            }
+        };
+    }
+}
+
+fn refresh_in(refresh_secs: u64, next_update: Option<u64>) -> u64 {
+    let Some(next_update) = next_update else {
+        return refresh_secs;
+    };
+    let now = unix_now();
+    if next_update <= now {
+        log::warn!("ocsp responder handed out an already stale response");
+        return RETRY_SECS.min(refresh_secs);
+    }
+    refresh_secs
+        .min((next_update - now) / 2)
+        .max(RETRY_SECS.min(refresh_secs))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+async fn http_post(uri: &Uri, body: &[u8]) -> Result<Vec<u8>, Error> {
+    let host = uri.host().unwrap_or_default();
+    let stream = TcpStream::connect((host, uri.port_u16().unwrap_or(80))).await?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            log::debug!("ocsp connection: {:?}", err);
+        }
+    });
+    let req = Request::post(uri.path_and_query().map_or("/", |pq| pq.as_str()))
+        .header(
+            HOST,
+            uri.authority().map_or(host, |authority| authority.as_str()),
+        )
+        .header(CONTENT_TYPE, "application/ocsp-request")
+        .body(Full::new(Bytes::copy_from_slice(body)))?;
+    let res = sender.send_request(req).await?;
+    if !res.status().is_success() {
+        bail!("got {}", res.status());
+    }
+    Ok(res.into_body().collect().await?.to_bytes().to_vec())
+}
+
+// DER, just enough of it
+
+const SEQUENCE: u8 = 0x30;
+const OCTET_STRING: u8 = 0x04;
+const INTEGER: u8 = 0x02;
+const ENUMERATED: u8 = 0x0a;
+const GENERALIZED_TIME: u8 = 0x18;
+// context-specific, constructed, [n]
+const fn explicit(n: u8) -> u8 {
+    0xa0 | n
+}
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+// OCSPRequest for one CertID, SHA-1 hashed like every responder expects
+fn request(leaf: &X509Certificate<'_>, issuer: &X509Certificate<'_>) -> Vec<u8> {
+    // sha1 (1.3.14.3.2.26), NULL parameters
+    let sha1 = [
+        0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00,
+    ];
+    let name_hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, leaf.issuer().as_raw());
+    let key_hash = digest(
+        &SHA1_FOR_LEGACY_USE_ONLY,
+        &issuer.public_key().subject_public_key.data,
+    );
+    let cert_id = [
+        sha1.as_slice(),
+        &tlv(OCTET_STRING, name_hash.as_ref()),
+        &tlv(OCTET_STRING, key_hash.as_ref()),
+        &tlv(INTEGER, leaf.raw_serial()),
+    ]
+    .concat();
+    // OCSPRequest { TBSRequest { requestList { Request { CertID } } } }
+    let req = tlv(SEQUENCE, &tlv(SEQUENCE, &cert_id));
+    tlv(SEQUENCE, &tlv(SEQUENCE, &tlv(SEQUENCE, &req)))
+}
+
+struct Der<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn new(buf: &'a [u8]) -> Der<'a> {
+        Der { buf }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn peek_tag(&self) -> Option<u8> {
+        self.buf.first().copied()
+    }
+
+    fn any(&mut self) -> Result<(u8, &'a [u8]), Error> {
+        let truncated = || anyhow!("truncated ocsp response");
+        let (&tag, rest) = self.buf.split_first().ok_or_else(truncated)?;
+        let (&first, rest) = rest.split_first().ok_or_else(truncated)?;
+        let (len, rest) = if first < 0x80 {
+            (first as usize, rest)
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 || rest.len() < count {
+                bail!("bad length in ocsp response");
+            }
+            let len = rest[..count]
+                .iter()
+                .fold(0usize, |len, b| (len << 8) | *b as usize);
+            (len, &rest[count..])
+        };
+        if rest.len() < len {
+            return Err(truncated());
+        }
+        self.buf = &rest[len..];
+        Ok((tag, &rest[..len]))
+    }
+
+    fn expect(&mut self, want: u8) -> Result<&'a [u8], Error> {
+        let (tag, content) = self.any()?;
+        if tag != want {
+            bail!("ocsp response: wanted tag {:#04x}, got {:#04x}", want, tag);
+        }
+        Ok(content)
+    }
+}
+
+// Ok(nextUpdate) when it's a successful response that covers serial
+fn check_response(response: &[u8], serial: &[u8]) -> Result<Option<u64>, Error> {
+    let mut ocsp_response = Der::new(Der::new(response).expect(SEQUENCE)?);
+    match ocsp_response.expect(ENUMERATED)? {
+        [0] => {}
+        [status] => bail!("responder said no (OCSPResponseStatus {})", status),
+        _ => bail!("garbled OCSPResponseStatus"),
+    }
+    let mut response_bytes =
+        Der::new(Der::new(ocsp_response.expect(explicit(0))?).expect(SEQUENCE)?);
+    response_bytes.any()?; // responseType, id-pkix-ocsp-basic by definition
+    let basic = response_bytes.expect(OCTET_STRING)?;
+    let mut basic = Der::new(Der::new(basic).expect(SEQUENCE)?);
+    let mut response_data = Der::new(basic.expect(SEQUENCE)?);
+    if response_data.peek_tag() == Some(explicit(0)) {
+        response_data.any()?; // version
+    }
+    response_data.any()?; // responderID
+    response_data.expect(GENERALIZED_TIME)?; // producedAt
+    let mut responses = Der::new(response_data.expect(SEQUENCE)?);
+    while !responses.is_empty() {
+        let mut single = Der::new(responses.expect(SEQUENCE)?);
+        let mut cert_id = Der::new(single.expect(SEQUENCE)?);
+        cert_id.any()?; // hashAlgorithm
+        cert_id.any()?; // issuerNameHash
+        cert_id.any()?; // issuerKeyHash
+        if cert_id.expect(INTEGER)? != serial {
+            continue;
+        }
+        let (status, _) = single.any()?;
+        match status & 0x1f {
+            0 => {}
+            1 => log::warn!("ocsp says our certificate is REVOKED, stapling it anyway"),
+            _ => log::warn!("ocsp responder doesn't know our certificate"),
+        }
+        single.expect(GENERALIZED_TIME)?; // thisUpdate
+        if single.peek_tag() == Some(explicit(0)) {
+            let next_update = Der::new(single.any()?.1).expect(GENERALIZED_TIME)?;
+            return Ok(Some(generalized_time(next_update)?));
+        }
+        return Ok(None);
+    }
+    bail!("ocsp response isn't about our certificate")
+}
+
+// YYYYMMDDHHMMSS[.fff]Z to unix seconds
+fn generalized_time(time: &[u8]) -> Result<u64, Error> {
+    let text = std::str::from_utf8(time)?;
+    let field = |range: std::ops::Range<usize>| -> Result<i64, Error> {
+        Ok(text
+            .get(range)
+            .ok_or_else(|| anyhow!("short GeneralizedTime {:?}", text))?
+            .parse()?)
+    };
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let seconds = field(8..10)? * 3600 + field(10..12)? * 60 + field(12..14)?;
+    // days from civil, Howard Hinnant's
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    u64::try_from(days * 86400 + seconds).map_err(|_| anyhow!("GeneralizedTime before 1970"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, sync::Mutex};
+
+    use hyper::{Response, StatusCode, body::Incoming, server::conn::http1, service::service_fn};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // made by testdata/ocsp/generate.py; checked with openssl ocsp
+    const ISSUER: &[u8] = include_bytes!("../testdata/ocsp/issuer.der");
+    const LEAF: &[u8] = include_bytes!("../testdata/ocsp/leaf.der");
+    const REQUEST: &[u8] = include_bytes!("../testdata/ocsp/request.der");
+    const GOOD: &[u8] = include_bytes!("../testdata/ocsp/good.der");
+    const REVOKED: &[u8] = include_bytes!("../testdata/ocsp/revoked.der");
+    const EXPIRED: &[u8] = include_bytes!("../testdata/ocsp/expired.der");
+    const OTHER_SERIAL: &[u8] = include_bytes!("../testdata/ocsp/other-serial.der");
+    const UNAUTHORIZED: &[u8] = include_bytes!("../testdata/ocsp/unauthorized.der");
+    // 2036-10-08T00:00:00Z and 2020-01-08T12:30:15Z
+    const NEXT_UPDATE: u64 = 2107036800;
+    const EXPIRED_NEXT_UPDATE: u64 = 1578486615;
+
+    fn serial() -> Vec<u8> {
+        X509Certificate::from_der(LEAF)
+            .unwrap()
+            .1
+            .raw_serial()
+            .to_vec()
+    }
+
+    #[test]
+    fn good_response() {
+        assert_eq!(check_response(GOOD, &serial()).unwrap(), Some(NEXT_UPDATE));
+    }
+
+    #[test]
+    fn revoked_response_still_staples() {
+        assert_eq!(
+            check_response(REVOKED, &serial()).unwrap(),
+            Some(NEXT_UPDATE)
+        );
+    }
+
+    #[test]
+    fn expired_next_update() {
+        assert_eq!(
+            check_response(EXPIRED, &serial()).unwrap(),
+            Some(EXPIRED_NEXT_UPDATE)
+        );
+        // already stale, so ask again soon rather than in refresh_secs
+        assert_eq!(
+            refresh_in(DEFAULT_REFRESH_SECS, Some(EXPIRED_NEXT_UPDATE)),
+            RETRY_SECS
+        );
+    }
+
+    #[test]
+    fn mismatched_cert_id() {
+        let err = check_response(OTHER_SERIAL, &serial()).unwrap_err();
+        assert!(err.to_string().contains("isn't about our certificate"));
+    }
+
+    #[test]
+    fn unsuccessful_and_truncated() {
+        let err = check_response(UNAUTHORIZED, &serial()).unwrap_err();
+        assert!(err.to_string().contains("OCSPResponseStatus 6"));
+        for cut in 0..GOOD.len() {
+            assert!(check_response(&GOOD[..cut], &serial()).is_err(), "{}", cut);
+        }
+    }
+
+    #[test]
+    fn request_matches_openssl() {
+        let (_, leaf) = X509Certificate::from_der(LEAF).unwrap();
+        let (_, issuer) = X509Certificate::from_der(ISSUER).unwrap();
+        assert_eq!(request(&leaf, &issuer), REQUEST);
+    }
+
+    // path, content type and body of each request it got
+    type Asked = Arc<Mutex<Vec<(String, String, Vec<u8>)>>>;
+
+    // an OCSP responder that answers everything with status and body
+    async fn responder(status: StatusCode, body: &'static [u8]) -> (String, Asked) {
+        let lsnr = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/ocsp/", lsnr.local_addr().unwrap());
+        let asked = Asked::default();
+        let log = asked.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = lsnr.accept().await.unwrap();
+                let log = log.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let log = log.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let content_type = req
+                            .headers()
+                            .get(CONTENT_TYPE)
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or_default()
+                            .to_string();
+                        let asked = req.into_body().collect().await.unwrap().to_bytes();
+                        log.lock()
+                            .unwrap()
+                            .push((path, content_type, asked.to_vec()));
+                        let mut res = Response::new(Full::new(Bytes::from_static(body)));
+                        *res.status_mut() = status;
+                        Ok::<_, Infallible>(res)
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        (url, asked)
+    }
+
+    fn spec(url: String) -> OcspSpec {
+        OcspSpec {
+            source: OcspSource::Responder {
+                url,
+                request: REQUEST.to_vec(),
+            },
+            serial: serial(),
+            refresh_secs: DEFAULT_REFRESH_SECS,
+        }
+    }
+
+    #[tokio::test]
+    async fn obtains_from_a_responder() {
+        let (url, asked) = responder(StatusCode::OK, GOOD).await;
+        let (staple, next_update) = spec(url).obtain().await.unwrap();
+        assert_eq!(staple, GOOD);
+        assert_eq!(next_update, Some(NEXT_UPDATE));
+        let asked = asked.lock().unwrap();
+        assert_eq!(asked.len(), 1);
+        let (path, content_type, body) = &asked[0];
+        assert_eq!(path, "/ocsp/");
+        assert_eq!(content_type, "application/ocsp-request");
+        assert_eq!(body, REQUEST);
+    }
+
+    #[tokio::test]
+    async fn refuses_what_a_responder_gets_wrong() {
+        let (url, _) = responder(StatusCode::INTERNAL_SERVER_ERROR, GOOD).await;
+        let err = spec(url).obtain().await.unwrap_err();
+        assert!(err.to_string().contains("500"), "{:#}", err);
+
+        let (url, _) = responder(StatusCode::OK, OTHER_SERIAL).await;
+        assert!(spec(url).obtain().await.is_err());
+
+        let (url, _) = responder(StatusCode::OK, UNAUTHORIZED).await;
+        assert!(spec(url).obtain().await.is_err());
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, anyhow, bail};
+use http_body_util::{BodyExt, Empty};
+use hyper::{Request, Uri, body::Bytes, header::HOST};
+use hyper_util::rt::TokioIo;
+use indexmap::IndexMap;
+use tokio::net::TcpStream;
+
+use crate::{
+    TlsMap,
+    conf::{Configuration, Diagnostics, MappingEntry, MappingFile, ProviderEntry},
+    index::MatchList,
+    matcher::Matcher,
+};
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// provider name to the last mappings it gave us that actually worked
+pub static PROVIDED: LazyLock<Mutex<IndexMap<String, IndexMap<String, MappingEntry>>>> =
+    LazyLock::new(|| Mutex::new(IndexMap::new()));
+
+pub enum RouteProvider {
+    // a JSON route table on disk, re-read when its mtime moves
+    FileProvider {
+        name: String,
+        path: PathBuf,
+        interval: Duration,
+        seen: Option<SystemTime>,
+    },
+    // a JSON route table from a plain-http endpoint
+    HttpProvider {
+        name: String,
+        uri: Uri,
+        interval: Duration,
+        seen: Option<Bytes>,
+    },
+}
+
+impl RouteProvider {
+    pub fn from_providerentry(name: &str, pe: &ProviderEntry) -> anyhow::Result<RouteProvider> {
+        match pe.kind.as_str() {
+            "file" => Ok(RouteProvider::FileProvider {
+                name: name.to_string(),
+                // relative to the config file, same as includes
+                path: crate::CLI_OPTIONS
+                    .conf
+                    .parent()
+                    .unwrap_or(Path::new(""))
+                    .join(pe.path.as_ref().ok_or_else(|| anyhow!("file needs path"))?),
+                interval: Duration::from_secs(pe.interval_secs.unwrap_or(5)),
+                seen: None,
+            }),
+            "http" => {
+                let uri: Uri = pe
+                    .url
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("http needs url"))?
+                    .parse()?;
+                if uri.scheme_str() != Some("http") || uri.host().is_none() {
+                    bail!("url must be http://host[:port]/path");
+                }
+                Ok(RouteProvider::HttpProvider {
+                    name: name.to_string(),
+                    uri,
+                    interval: Duration::from_secs(pe.interval_secs.unwrap_or(30)),
+                    seen: None,
+                })
+            }
+            other => Err(anyhow!("unknown provider kind {:?}", other)),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            RouteProvider::FileProvider { name, .. } | RouteProvider::HttpProvider { name, .. } => {
+                name
+            }
+        }
+    }
+
+    fn interval(&self) -> Duration {
+        match self {
+            RouteProvider::FileProvider { interval, .. }
+            | RouteProvider::HttpProvider { interval, .. } => *interval,
+        }
+    }
+
+    // None when nothing changed since last time
+    pub async fn fetch(&mut self) -> anyhow::Result<Option<IndexMap<String, MappingEntry>>> {
+        let (raw, origin) = match self {
+            RouteProvider::FileProvider { path, seen, .. } => {
+                let modified = tokio::fs::metadata(&path).await?.modified()?;
+                if *seen == Some(modified) {
+                    return Ok(None);
+                }
+                let raw = tokio::fs::read(&path).await?;
+                *seen = Some(modified);
+                (Bytes::from(raw), path.clone())
+            }
+            RouteProvider::HttpProvider { uri, seen, .. } => {
+                let raw = tokio::time::timeout(HTTP_TIMEOUT, http_get(uri))
+                    .await
+                    .context("timed out")??;
+                if seen.as_ref() == Some(&raw) {
+                    return Ok(None);
+                }
+                *seen = Some(raw.clone());
+                (raw, PathBuf::from(uri.to_string()))
+            }
+        };
+        let mut table: MappingFile = serde_json::from_slice(&raw)?;
+        for mapspec in table.mapping.values_mut() {
+            mapspec.origin = Some(origin.clone());
+        }
+        Ok(Some(table.mapping))
+    }
+}
+
+async fn http_get(uri: &Uri) -> anyhow::Result<Bytes> {
+    let host = uri.host().unwrap_or_default();
+    let stream = TcpStream::connect((host, uri.port_u16().unwrap_or(80))).await?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            tracing::debug!("provider connection: {:?}", err);
+        }
+    });
+    let req = Request::get(uri.path_and_query().map_or("/", |pq| pq.as_str()))
+        .header(
+            HOST,
+            uri.authority().map_or(host, |authority| authority.as_str()),
+        )
+        .body(Empty::<Bytes>::new())?;
+    let res = sender.send_request(req).await?;
+    if !res.status().is_success() {
+        bail!("got {}", res.status());
+    }
+    Ok(res.into_body().collect().await?.to_bytes())
+}
+
+pub fn validate(cfg: &Configuration, diag: &mut Diagnostics) {
+    for (name, pe) in cfg.provider.iter().flatten() {
+        if let Err(err) = RouteProvider::from_providerentry(name, pe) {
+            diag.error(format!("provider {}: {:#}", name, err));
+        }
+    }
+}
+
+// static mappings first, then each provider's in config order
+pub fn build_matchlist(
+    cfg: &Configuration,
+    provided: &IndexMap<String, IndexMap<String, MappingEntry>>,
+    tlses: &TlsMap,
+) -> Result<(MatchList, Diagnostics), Diagnostics> {
+    let mut diag = Diagnostics::default();
+    let mappings = cfg.mapping.iter().chain(provided.values().flatten());
+    let matchers = Matcher::from_mappings(cfg, mappings, tlses, &mut diag);
+    if !diag.is_ok() {
+        return Err(diag);
+    }
+    match MatchList::new(matchers) {
+        Ok(matchers) => Ok((matchers, diag)),
+        Err(err) => {
+            diag.error(format!("couldn't index mappings: {}", err));
+            Err(diag)
+        }
+    }
+}
+
+// try new mappings on for size next to everyone else's
+// anything wrong and the provider stays on its last-known-good
+fn offer(name: &str, mappings: IndexMap<String, MappingEntry>) -> Result<(), Diagnostics> {
+    let mut provided = PROVIDED.lock().unwrap();
+    let mut candidate = provided.clone();
+    candidate.insert(name.to_string(), mappings);
+    let (matchers, diag) = build_matchlist(&crate::fullcfg(), &candidate, &crate::tlsmap())?;
+    for warning in diag.warnings.iter() {
+        tracing::warn!("provider {}: {}", name, warning);
+    }
+    *crate::MATCHLIST.write().unwrap() = Some(Arc::new(matchers));
+    *provided = candidate;
+    Ok(())
+}
+
+// one look at the provider, and whatever comes of it
+async fn poll(provider: &mut RouteProvider) {
+    match provider.fetch().await {
+        Ok(None) => {}
+        Ok(Some(mappings)) => {
+            let count = mappings.len();
+            match offer(provider.name(), mappings) {
+                Ok(()) => {
+                    tracing::info!("provider {} updated, {} mappings", provider.name(), count)
+                }
+                Err(diag) => tracing::warn!(
+                    "provider {} rejected, keeping last-known-good:\n{}",
+                    provider.name(),
+                    diag
+                ),
+            }
+        }
+        Err(err) => tracing::warn!(
+            "provider {} failed, keeping last-known-good: {:#}",
+            provider.name(),
+            err
+        ),
+    }
+}
+
+pub async fn run(mut provider: RouteProvider) {
+    loop {
+        poll(&mut provider).await;
+        tokio::time::sleep(provider.interval()).await;
+    }
+}
+
+// providers come from the config at startup; a reload keeps them as they were
+pub fn spawn_providers() {
+    for (name, pe) in crate::fullcfg().provider.iter().flatten() {
+        match RouteProvider::from_providerentry(name, pe) {
+            Ok(provider) => {
+                tokio::spawn(run(provider));
+            }
+            Err(err) => tracing::error!("provider {}: {:#}", name, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::UNIX_EPOCH,
+    };
+
+    use http_body_util::Full;
+    use hyper::{Response, StatusCode, body::Incoming, server::conn::http1, service::service_fn};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // FULLCFG, MATCHLIST and PROVIDED are everyone's, one test at a time
+    const CONFIG: &str = r#"
+[listener]
+addr = "127.0.0.1"
+port = 0
+
+[mapping.static]
+exact = "static.example"
+downstreams = ["127.0.0.1:9"]
+"#;
+
+    fn table(exact: &str) -> String {
+        format!(
+            r#"{{"mapping": {{"dyn": {{"exact": "{}", "downstreams": ["127.0.0.1:9"]}}}}}}"#,
+            exact
+        )
+    }
+
+    // takes the static mapping's name, so it can't go in
+    const CLASHING: &str =
+        r#"{"mapping": {"static": {"exact": "other.example", "downstreams": ["127.0.0.1:9"]}}}"#;
+
+    // a fresh config made live, like load() would, and nothing provided yet
+    fn install(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lurkr-{}-{}", test, std::process::id()));
+        crate::install_for_test(&dir, CONFIG);
+        PROVIDED.lock().unwrap().clear();
+        dir
+    }
+
+    fn routed(name: &str) -> String {
+        crate::matchlist()
+            .lookup(name, None)
+            .unwrap()
+            .rulename()
+            .to_string()
+    }
+
+    fn provided(name: &str) -> Vec<String> {
+        PROVIDED.lock().unwrap()[name].keys().cloned().collect()
+    }
+
+    // a new table with an mtime that's sure to differ from the last
+    fn rewrite(path: &Path, table: &str, mtime: u64) {
+        std::fs::write(path, table).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_provider() {
+        let _globals = crate::TEST_GLOBALS.lock().await;
+        let dir = install("file-provider");
+        let path = dir.join("routes.json");
+        rewrite(&path, &table("dyn.example"), 1);
+        let mut provider = RouteProvider::FileProvider {
+            name: "files".to_string(),
+            path: path.clone(),
+            interval: Duration::from_secs(5),
+            seen: None,
+        };
+        assert_eq!(routed("dyn.example"), "__default");
+
+        // applied, after the static mappings
+        poll(&mut provider).await;
+        assert_eq!(routed("dyn.example"), "dyn");
+        assert_eq!(routed("static.example"), "static");
+        assert_eq!(provided("files"), ["dyn"]);
+        let live = crate::matchlist();
+
+        // same mtime, not even read again
+        poll(&mut provider).await;
+        assert!(Arc::ptr_eq(&live, &crate::matchlist()));
+
+        // rejected, and the last-known-good stays live
+        rewrite(&path, CLASHING, 2);
+        poll(&mut provider).await;
+        assert!(Arc::ptr_eq(&live, &crate::matchlist()));
+        assert_eq!(routed("dyn.example"), "dyn");
+        assert_eq!(provided("files"), ["dyn"]);
+
+        // and a good table after that goes in
+        rewrite(&path, &table("next.example"), 3);
+        poll(&mut provider).await;
+        assert_eq!(routed("next.example"), "dyn");
+        assert_eq!(routed("dyn.example"), "__default");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // serves whatever status and table are set right now, counting asks
+    async fn route_server(
+        answer: Arc<Mutex<(StatusCode, String)>>,
+        asked: Arc<AtomicUsize>,
+    ) -> Uri {
+        let lsnr = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}/routes.json", lsnr.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = lsnr.accept().await.unwrap();
+                let (answer, asked) = (answer.clone(), asked.clone());
+                let service = service_fn(move |_: Request<Incoming>| {
+                    asked.fetch_add(1, Ordering::SeqCst);
+                    let (status, table) = answer.lock().unwrap().clone();
+                    let mut res = Response::new(Full::new(Bytes::from(table)));
+                    *res.status_mut() = status;
+                    async move { Ok::<_, Infallible>(res) }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        uri.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn http_provider() {
+        let _globals = crate::TEST_GLOBALS.lock().await;
+        let dir = install("http-provider");
+        let answer = Arc::new(Mutex::new((StatusCode::OK, table("dyn.example"))));
+        let asked = Arc::new(AtomicUsize::new(0));
+        let mut provider = RouteProvider::HttpProvider {
+            name: "remote".to_string(),
+            uri: route_server(answer.clone(), asked.clone()).await,
+            interval: Duration::from_secs(30),
+            seen: None,
+        };
+
+        // applied
+        poll(&mut provider).await;
+        assert_eq!(routed("dyn.example"), "dyn");
+        assert_eq!(provided("remote"), ["dyn"]);
+        let live = crate::matchlist();
+
+        // asked again, same body, nothing rebuilt
+        poll(&mut provider).await;
+        assert_eq!(asked.load(Ordering::SeqCst), 2);
+        assert!(Arc::ptr_eq(&live, &crate::matchlist()));
+
+        // rejected, unparseable, or an error status: last-known-good stays
+        for (status, table) in [
+            (StatusCode::OK, CLASHING.to_string()),
+            (StatusCode::OK, "{\"mapping\": ".to_string()),
+            (StatusCode::INTERNAL_SERVER_ERROR, table("next.example")),
+        ] {
+            *answer.lock().unwrap() = (status, table.clone());
+            poll(&mut provider).await;
+            assert!(Arc::ptr_eq(&live, &crate::matchlist()), "{}", table);
+            assert_eq!(routed("dyn.example"), "dyn");
+            assert_eq!(provided("remote"), ["dyn"]);
+        }
+        assert_eq!(asked.load(Ordering::SeqCst), 5);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
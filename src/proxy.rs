@@ -7,17 +7,49 @@ use tokio::{
 };
 use tokio_rustls::{TlsAcceptor, server::TlsStream};
 
-pub(crate) async fn tcp_proxy_addr(incoming: TcpStream, addr: &String) -> io::Result<()> {
-    let outgoing = TcpStream::connect(addr).await?;
-    tcp_proxy_stream(incoming, outgoing).await
+use crate::authz::ClientAuthz;
+use crate::track::{ConnInfo, Counted};
+
+pub(crate) async fn tcp_proxy_addr(
+    incoming: TcpStream,
+    addr: &str,
+    splice: bool,
+    conn: &ConnInfo,
+) -> io::Result<()> {
+    let outgoing = crate::track::connect_downstream(addr).await?;
+    tcp_proxy_stream(incoming, outgoing, splice, conn).await
 }
 
-pub(crate) async fn tcp_proxy_stream(
+// pub only so benches/splice.rs can drive it; not API
+#[doc(hidden)]
+pub async fn tcp_proxy_stream(
     mut incoming: TcpStream,
     mut outgoing: TcpStream,
+    splice: bool,
+    conn: &ConnInfo,
 ) -> io::Result<()> {
-    let (mut ri, mut wi) = incoming.split();
-    let (mut ro, mut wo) = outgoing.split();
+    #[cfg(target_os = "linux")]
+    if splice {
+        // zero-copy if the kernel will give us pipes, otherwise copy like normal
+        match (crate::splice::Pipe::new(), crate::splice::Pipe::new()) {
+            (Ok(lpipe), Ok(rpipe)) => {
+                return crate::splice::splice_proxy_stream(incoming, outgoing, lpipe, rpipe, conn)
+                    .await;
+            }
+            (Err(err), _) | (_, Err(err)) => {
+                tracing::debug!("no splice pipes ({:?}), falling back to copy", err);
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    if splice {
+        tracing::debug!("splice is linux-only, falling back to copy");
+    }
+
+    let (ri, mut wi) = incoming.split();
+    let (ro, mut wo) = outgoing.split();
+    let mut ri = Counted::new(ri, &conn.bytes_up);
+    let mut ro = Counted::new(ro, &conn.bytes_down);
 
     let left = async move {
         tokio::io::copy(&mut ri, &mut wo).await?;
@@ -54,9 +86,12 @@ pub(crate) async fn tcp_proxy_stream(
 pub(crate) async fn tls_proxy_stream(
     incoming: TlsStream<TcpStream>,
     outgoing: TcpStream,
+    conn: &ConnInfo,
 ) -> io::Result<()> {
-    let (mut ri, mut wi) = tokio::io::split(incoming);
-    let (mut ro, mut wo) = tokio::io::split(outgoing);
+    let (ri, mut wi) = tokio::io::split(incoming);
+    let (ro, mut wo) = tokio::io::split(outgoing);
+    let mut ri = Counted::new(ri, &conn.bytes_up);
+    let mut ro = Counted::new(ro, &conn.bytes_down);
 
     let left = async move {
         tokio::io::copy(&mut ri, &mut wo).await?;
@@ -93,10 +128,22 @@ pub(crate) async fn tls_proxy_stream(
 
 pub(crate) async fn tls_proxy_addr(
     incoming: TcpStream,
-    addr: &String,
+    addr: &str,
     acceptor: Arc<TlsAcceptor>,
+    authz: Option<&ClientAuthz>,
+    conn: &ConnInfo,
 ) -> io::Result<()> {
-    let outgoing = TcpStream::connect(addr).await?;
+    let Some(authz) = authz else {
+        let outgoing = crate::track::connect_downstream(addr).await?;
+        let plaintext_stream = acceptor.accept(incoming).await?;
+        return tls_proxy_stream(plaintext_stream, outgoing, conn).await;
+    };
+    // the downstream doesn't hear about clients we turn away
     let plaintext_stream = acceptor.accept(incoming).await?;
-    tls_proxy_stream(plaintext_stream, outgoing).await
+    if !authz.admits(&plaintext_stream, conn) {
+        authz.deny(plaintext_stream).await;
+        return Ok(());
+    }
+    let outgoing = crate::track::connect_downstream(addr).await?;
+    tls_proxy_stream(plaintext_stream, outgoing, conn).await
 }
@@ -0,0 +1,137 @@
+// what's this then: the first bytes of a connection that might not be TLS
+// anything we recognise can go to a [fallback] downstream, like sslh does
+
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+
+use crate::conf::Fallback;
+use crate::dispatcher::Dispatcher;
+use crate::track::ConnInfo;
+
+// how long a client gets to say something before it's server-first
+const DEFAULT_SERVER_FIRST: Duration = Duration::from_millis(2000);
+// a few peeks for the bytes that tell the protocols apart
+const PEEKS: usize = 20;
+const PEEK_WAIT: Duration = Duration::from_millis(25);
+
+// postgres startup, SSLRequest, GSSENCRequest and CancelRequest codes
+const PG_CODES: [[u8; 4]; 4] = [
+    [0x00, 0x03, 0x00, 0x00],
+    [0x04, 0xd2, 0x16, 0x2f],
+    [0x04, 0xd2, 0x16, 0x30],
+    [0x04, 0xd2, 0x16, 0x2e],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sniffed {
+    Tls,
+    Http,
+    Ssh,
+    Postgres,
+    // the client never said anything: MySQL, SMTP and friends
+    ServerFirst,
+    Other,
+    // nothing at all, just a FIN
+    Eof,
+}
+
+impl Sniffed {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sniffed::Tls => "tls",
+            Sniffed::Http => "http",
+            Sniffed::Ssh => "ssh",
+            Sniffed::Postgres => "postgres",
+            Sniffed::ServerFirst => "server_first",
+            Sniffed::Other => "other",
+            Sniffed::Eof => "eof",
+        }
+    }
+}
+
+// None when it's too soon to say
+fn classify(buf: &[u8]) -> Option<Sniffed> {
+    // a TLS handshake record, which nothing else here starts with
+    if buf[0] == 0x16 {
+        return Some(Sniffed::Tls);
+    }
+    if b"SSH-".starts_with(&buf[..buf.len().min(4)]) {
+        return (buf.len() >= 4).then_some(Sniffed::Ssh);
+    }
+    // big-endian length up front, which is tiny, then the code
+    if buf[0] == 0 {
+        if buf.len() < 8 {
+            return None;
+        }
+        return Some(if PG_CODES.iter().any(|code| buf[4..8] == *code) {
+            Sniffed::Postgres
+        } else {
+            Sniffed::Other
+        });
+    }
+    if crate::http::looks_like_http(buf) {
+        return Some(Sniffed::Http);
+    }
+    Some(Sniffed::Other)
+}
+
+// peek until the protocol is plain; peekbuf holds what was seen
+pub async fn sniff(
+    socket: &TcpStream,
+    peekbuf: &mut [u8],
+    fallback: Option<&Fallback>,
+) -> std::io::Result<(Sniffed, usize)> {
+    // only bother waiting on a silent client if there's somewhere to send it
+    let server_first = fallback.and_then(|fallback| {
+        fallback.server_first.as_ref().map(|_| {
+            fallback
+                .server_first_timeout_ms
+                .map_or(DEFAULT_SERVER_FIRST, Duration::from_millis)
+        })
+    });
+    let mut rsz = match server_first {
+        Some(wait) => match tokio::time::timeout(wait, socket.peek(peekbuf)).await {
+            Ok(peeked) => peeked?,
+            Err(_) => return Ok((Sniffed::ServerFirst, 0)),
+        },
+        None => socket.peek(peekbuf).await?,
+    };
+    for _ in 0..PEEKS {
+        if rsz == 0 {
+            return Ok((Sniffed::Eof, 0));
+        }
+        if let Some(sniffed) = classify(&peekbuf[..rsz]) {
+            return Ok((sniffed, rsz));
+        }
+        // peek doesn't wait for more, so we do
+        tokio::time::sleep(PEEK_WAIT).await;
+        rsz = socket.peek(peekbuf).await?;
+    }
+    Ok((Sniffed::Other, rsz))
+}
+
+// hand a non-TLS connection to its [fallback] downstream, if it has one
+pub async fn fallback(sniffed: Sniffed, socket: TcpStream, conn: &ConnInfo) {
+    let cfg = crate::fullcfg();
+    let downstreams = cfg.fallback.as_ref().and_then(|fallback| match sniffed {
+        Sniffed::Http => fallback.http.as_ref(),
+        Sniffed::Ssh => fallback.ssh.as_ref(),
+        Sniffed::Postgres => fallback.postgres.as_ref(),
+        Sniffed::ServerFirst => fallback.server_first.as_ref(),
+        Sniffed::Other => fallback.other.as_ref(),
+        Sniffed::Tls | Sniffed::Eof => None,
+    });
+    let Some(downstreams) = downstreams.filter(|downstreams| !downstreams.is_empty()) else {
+        tracing::debug!("{} connection detected, no fallback for it", sniffed.name());
+        return;
+    };
+    tracing::debug!("{} connection detected, falling back", sniffed.name());
+    conn.rule.set(format!("__fallback_{}", sniffed.name())).ok();
+    Dispatcher::TCPDownstreamDispatcher {
+        downstreams: downstreams.clone(),
+        splice: false,
+    }
+    .do_dispatch(socket, conn)
+    .await
+}
@@ -0,0 +1,197 @@
+// sockets the way [listener] and [downstream.*] want them, for what
+// TcpListener::bind and TcpStream::connect don't let you ask for
+
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::{
+    io,
+    net::{TcpListener, TcpSocket, TcpStream, lookup_host},
+};
+
+use crate::conf::{Configuration, Diagnostics, Listener};
+
+pub const DEFAULT_BACKLOG: u32 = 1024;
+
+// bind and listen with [listener]'s options, first address that works
+pub async fn bind(addr: &str, opts: &Listener) -> io::Result<TcpListener> {
+    let mut last = None;
+    for resolved in lookup_host(addr).await? {
+        match bind_one(resolved, opts) {
+            Ok(lsnr) => return Ok(lsnr),
+            Err(err) => last = Some(err),
+        }
+    }
+    Err(last.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} resolves to nothing", addr),
+        )
+    }))
+}
+
+fn bind_one(addr: SocketAddr, opts: &Listener) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // what TcpListener::bind would have done
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    #[cfg(all(
+        unix,
+        not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))
+    ))]
+    if opts.reuseport == Some(true) {
+        socket.set_reuse_port(true)?;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        if opts.transparent == Some(true) {
+            if addr.is_ipv4() {
+                socket.set_ip_transparent_v4(true)?;
+            } else {
+                socket.set_ip_transparent_v6(true)?;
+            }
+        }
+        if opts.freebind == Some(true) {
+            if addr.is_ipv4() {
+                socket.set_freebind_v4(true)?;
+            } else {
+                socket.set_freebind_v6(true)?;
+            }
+        }
+        if let Some(queue) = opts.fastopen {
+            set_tcp_int(&socket, libc::TCP_FASTOPEN, queue as libc::c_int)?;
+        }
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(opts.backlog.unwrap_or(DEFAULT_BACKLOG).min(i32::MAX as u32) as i32)?;
+    TcpListener::from_std(socket.into())
+}
+
+// keepalive and nodelay, on an accepted or connected socket
+pub fn tune(
+    stream: &TcpStream,
+    keepalive_secs: Option<u64>,
+    nodelay: Option<bool>,
+) -> io::Result<()> {
+    let sock = SockRef::from(stream);
+    if let Some(secs) = keepalive_secs {
+        sock.set_tcp_keepalive(&TcpKeepalive::new().with_time(Duration::from_secs(secs)))?;
+    }
+    if let Some(nodelay) = nodelay {
+        sock.set_tcp_nodelay(nodelay)?;
+    }
+    Ok(())
+}
+
+// a client the listener just handed us
+pub fn tune_accepted(stream: &TcpStream, opts: &Listener) {
+    if let Err(err) = tune(stream, opts.keepalive_secs, opts.nodelay) {
+        tracing::debug!("couldn't set client socket options: {:?}", err);
+    }
+}
+
+// a downstream connection, with whatever the [downstream.*] listing
+// its address asks for
+pub async fn connect(addr: &str) -> io::Result<TcpStream> {
+    let cfg = crate::fullcfg();
+    let Some(opts) = cfg
+        .downstream
+        .iter()
+        .flat_map(|downstreams| downstreams.values())
+        .find(|entry| entry.addrs.iter().any(|listed| listed == addr))
+    else {
+        return TcpStream::connect(addr).await;
+    };
+    let stream = if opts.fastopen == Some(true) {
+        connect_fastopen(addr).await?
+    } else {
+        TcpStream::connect(addr).await?
+    };
+    tune(&stream, opts.keepalive_secs, opts.nodelay)?;
+    Ok(stream)
+}
+
+// the first write goes out with the SYN, when there's a cookie for it
+async fn connect_fastopen(addr: &str) -> io::Result<TcpStream> {
+    let mut last = None;
+    for resolved in lookup_host(addr).await? {
+        let socket = if resolved.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        #[cfg(target_os = "linux")]
+        set_tcp_int(&socket, libc::TCP_FASTOPEN_CONNECT, 1)?;
+        match socket.connect(resolved).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last = Some(err),
+        }
+    }
+    Err(last.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} resolves to nothing", addr),
+        )
+    }))
+}
+
+#[cfg(target_os = "linux")]
+fn set_tcp_int<S: std::os::fd::AsRawFd>(
+    socket: &S,
+    option: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    let set = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if set < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// options this platform can't do get ignored, but not quietly
+pub fn validate(cfg: &Configuration, diag: &mut Diagnostics) {
+    let listener = &cfg.listener;
+    if !cfg!(target_os = "linux") {
+        for (option, set) in [
+            ("transparent", listener.transparent == Some(true)),
+            ("freebind", listener.freebind == Some(true)),
+            ("fastopen", listener.fastopen.is_some()),
+        ] {
+            if set {
+                diag.warn(format!("listener: {} is linux-only, ignored", option));
+            }
+        }
+    }
+    if !cfg!(unix) && listener.reuseport == Some(true) {
+        diag.warn("listener: reuseport needs a unix, ignored".to_string());
+    }
+    let mut seen = HashMap::<&String, &String>::new();
+    for (name, entry) in cfg.downstream.iter().flatten() {
+        if entry.addrs.is_empty() {
+            diag.warn(format!("downstream {}: no addrs, applies to nothing", name));
+        }
+        if !cfg!(target_os = "linux") && entry.fastopen == Some(true) {
+            diag.warn(format!(
+                "downstream {}: fastopen is linux-only, ignored",
+                name
+            ));
+        }
+        for addr in entry.addrs.iter() {
+            if let Some(earlier) = seen.insert(addr, name) {
+                diag.error(format!(
+                    "downstream {}: {} is already in downstream {}",
+                    name, addr, earlier
+                ));
+            }
+        }
+    }
+}
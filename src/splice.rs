@@ -0,0 +1,124 @@
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::{
+    io::{self, Interest},
+    net::TcpStream,
+    select,
+};
+
+use crate::track::ConnInfo;
+
+// how much we ask the kernel to shovel per splice(2)
+// a default pipe holds 64KiB so there's no point asking for more
+const SPLICE_SIZE: usize = 65536;
+
+// splice(2) needs one end to be a pipe, so each direction
+// goes socket -> pipe -> socket and never visits user space
+pub(crate) struct Pipe {
+    rd: OwnedFd,
+    wr: OwnedFd,
+}
+
+impl Pipe {
+    pub(crate) fn new() -> io::Result<Pipe> {
+        let mut fds = [0 as libc::c_int; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: pipe2 just handed us these and nobody else owns them
+        Ok(unsafe {
+            Pipe {
+                rd: OwnedFd::from_raw_fd(fds[0]),
+                wr: OwnedFd::from_raw_fd(fds[1]),
+            }
+        })
+    }
+}
+
+fn splice(fd_in: RawFd, fd_out: RawFd, len: usize) -> io::Result<usize> {
+    let moved = unsafe {
+        libc::splice(
+            fd_in,
+            std::ptr::null_mut(),
+            fd_out,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if moved < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(moved as usize)
+    }
+}
+
+fn shutdown_write(sock: &TcpStream) -> io::Result<()> {
+    if unsafe { libc::shutdown(sock.as_raw_fd(), libc::SHUT_WR) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// the pipe is always drained before the next read, so EAGAIN
+// on either side means the socket isn't ready, which is exactly
+// what async_io waits out for us
+pub(crate) async fn splice_copy(
+    src: &TcpStream,
+    dst: &TcpStream,
+    pipe: &Pipe,
+    counter: &AtomicU64,
+) -> io::Result<u64> {
+    let mut total: u64 = 0;
+    loop {
+        let filled = src
+            .async_io(Interest::READABLE, || {
+                splice(src.as_raw_fd(), pipe.wr.as_raw_fd(), SPLICE_SIZE)
+            })
+            .await?;
+        if filled == 0 {
+            return Ok(total);
+        }
+        let mut pending = filled;
+        while pending > 0 {
+            pending -= dst
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe.rd.as_raw_fd(), dst.as_raw_fd(), pending)
+                })
+                .await?;
+        }
+        total += filled as u64;
+        counter.fetch_add(filled as u64, Ordering::Relaxed);
+    }
+}
+
+pub(crate) async fn splice_proxy_stream(
+    incoming: TcpStream,
+    outgoing: TcpStream,
+    lpipe: Pipe,
+    rpipe: Pipe,
+    conn: &ConnInfo,
+) -> io::Result<()> {
+    let left = async {
+        splice_copy(&incoming, &outgoing, &lpipe, &conn.bytes_up).await?;
+        // eat the socket close error
+        shutdown_write(&outgoing).ok();
+        Ok::<_, io::Error>(())
+    };
+    let right = async {
+        splice_copy(&outgoing, &incoming, &rpipe, &conn.bytes_down).await?;
+        // eat the socket close error
+        shutdown_write(&incoming).ok();
+        Ok::<_, io::Error>(())
+    };
+
+    let mut stopper = crate::CONNECTION_STOP.1.clone();
+    select! {
+        biased;
+        _ = stopper.changed() => {log::debug!("stopping connection"); },
+        _ = left => {},
+        _ = right => {},
+    }
+    Ok(())
+}
@@ -0,0 +1,330 @@
+// STARTTLS: play server for the plaintext preamble until the client
+// upgrades, route on the ClientHello that comes next, then play client
+// to the downstream with the same preamble and get out of the way
+// passthrough only, the TLS is the downstream's to terminate
+
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::{Context, anyhow, bail};
+use rand::seq::IndexedRandom;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::conf::StartTlsProtocol;
+use crate::dispatcher::Dispatcher;
+use crate::fingerprint::Fingerprint;
+use crate::track::{ConnInfo, Tracked};
+
+const PEEK_SIZE: usize = 10240;
+const MAX_LINE: u64 = 4096;
+// a person typing at telnet gets this long to get to STARTTLS
+const CLIENT_PREAMBLE: Duration = Duration::from_secs(60);
+const DOWNSTREAM_PREAMBLE: Duration = Duration::from_secs(30);
+
+// postgres wants these for SSLRequest and GSSENCRequest
+const PG_SSLREQUEST: [u8; 8] = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x2f];
+const PG_GSSENCREQUEST: [u8; 8] = [0, 0, 0, 8, 0x04, 0xd2, 0x16, 0x30];
+
+// what the client said that the downstream needs to hear too
+struct Preamble {
+    // SMTP's EHLO/HELO
+    hello: Option<Vec<u8>>,
+    // the upgrade itself, verbatim
+    starttls: Vec<u8>,
+}
+
+pub async fn handle_connection(
+    socket: TcpStream,
+    client: SocketAddr,
+    protocol: StartTlsProtocol,
+    hostname: String,
+) {
+    let conn = Tracked::new(client);
+    if let Err(err) = upgrade_and_route(socket, &conn, protocol, &hostname).await {
+        tracing::debug!("{:?} starttls: {:#}", protocol, err);
+    }
+}
+
+async fn upgrade_and_route(
+    socket: TcpStream,
+    conn: &ConnInfo,
+    protocol: StartTlsProtocol,
+    hostname: &str,
+) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(socket);
+    let preamble = tokio::time::timeout(
+        CLIENT_PREAMBLE,
+        serve_preamble(protocol, &mut reader, hostname),
+    )
+    .await
+    .context("client took too long")??;
+    let socket = unbuffer(reader).context("client pipelined past STARTTLS")?;
+
+    let mut peekbuf = [0; PEEK_SIZE];
+    let hello = crate::clienthello::peek(&socket, &mut peekbuf).await?;
+    let fingerprint = Fingerprint::of(&hello);
+    conn.fingerprint.set(fingerprint.clone()).ok();
+    let sni = hello.server_name();
+    tracing::debug!("{:?} starttls indicated: {:?}", protocol, sni);
+    if let Some(sni) = &sni {
+        conn.sni.set(sni.clone()).ok();
+    }
+    let Some((rulename, dispatcher)) =
+        Dispatcher::from_indicated(sni.as_deref().unwrap_or(""), Some(&fingerprint))
+    else {
+        bail!("no dispatcher for indicated");
+    };
+    conn.rule.set(rulename).ok();
+    match &dispatcher {
+        Dispatcher::TCPDownstreamDispatcher {
+            downstreams,
+            splice,
+        } => {
+            let chosen = downstreams
+                .choose(&mut rand::rng())
+                .ok_or_else(|| anyhow!("no downstreams in dispatcher"))?;
+            conn.downstream.set(chosen.clone()).ok();
+            let mut upstream = BufReader::new(crate::track::connect_downstream(chosen).await?);
+            tokio::time::timeout(
+                DOWNSTREAM_PREAMBLE,
+                replay_preamble(protocol, &mut upstream, &preamble),
+            )
+            .await
+            .context("downstream took too long")??;
+            let upstream = unbuffer(upstream).context("downstream talked past STARTTLS")?;
+            crate::proxy::tcp_proxy_stream(socket, upstream, *splice, conn).await?;
+        }
+        // nothing to negotiate for a rejection
+        Dispatcher::TLSAlertDispatcher { .. } | Dispatcher::CloseDispatcher { .. } => {
+            dispatcher.do_dispatch(socket, conn).await
+        }
+        _ => bail!("rule needs plain downstreams, STARTTLS is passthrough only"),
+    }
+    Ok(())
+}
+
+// neither side gets to send anything between STARTTLS and the ClientHello
+fn unbuffer(reader: BufReader<TcpStream>) -> anyhow::Result<TcpStream> {
+    if !reader.buffer().is_empty() {
+        bail!("{} unexpected bytes", reader.buffer().len());
+    }
+    Ok(reader.into_inner())
+}
+
+async fn read_line(reader: &mut BufReader<TcpStream>) -> anyhow::Result<Vec<u8>> {
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_LINE)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        bail!("hung up");
+    }
+    if !line.ends_with(b"\n") {
+        bail!("line too long");
+    }
+    Ok(line)
+}
+
+async fn say(reader: &mut BufReader<TcpStream>, what: &str) -> anyhow::Result<()> {
+    reader.get_mut().write_all(what.as_bytes()).await?;
+    Ok(())
+}
+
+// first word, shouted
+fn verb(line: &[u8], skip: usize) -> String {
+    String::from_utf8_lossy(line)
+        .split_whitespace()
+        .nth(skip)
+        .unwrap_or_default()
+        .to_ascii_uppercase()
+}
+
+async fn serve_preamble(
+    protocol: StartTlsProtocol,
+    reader: &mut BufReader<TcpStream>,
+    hostname: &str,
+) -> anyhow::Result<Preamble> {
+    match protocol {
+        StartTlsProtocol::Smtp => {
+            say(reader, &format!("220 {} ESMTP lurkr\r\n", hostname)).await?;
+            let mut hello = None;
+            loop {
+                let line = read_line(reader).await?;
+                match verb(&line, 0).as_str() {
+                    "EHLO" => {
+                        say(reader, &format!("250-{}\r\n250 STARTTLS\r\n", hostname)).await?;
+                        hello = Some(line);
+                    }
+                    "HELO" => {
+                        say(reader, &format!("250 {}\r\n", hostname)).await?;
+                        hello = Some(line);
+                    }
+                    "STARTTLS" => {
+                        say(reader, "220 2.0.0 Ready to start TLS\r\n").await?;
+                        return Ok(Preamble {
+                            hello,
+                            starttls: line,
+                        });
+                    }
+                    "NOOP" | "RSET" => say(reader, "250 2.0.0 OK\r\n").await?,
+                    "QUIT" => {
+                        say(reader, "221 2.0.0 Bye\r\n").await?;
+                        bail!("client quit");
+                    }
+                    _ => say(reader, "530 5.7.0 Must issue a STARTTLS command first\r\n").await?,
+                }
+            }
+        }
+        StartTlsProtocol::Imap => {
+            let capability = "CAPABILITY IMAP4rev1 STARTTLS LOGINDISABLED";
+            say(
+                reader,
+                &format!("* OK [{}] {} ready\r\n", capability, hostname),
+            )
+            .await?;
+            loop {
+                let line = read_line(reader).await?;
+                let tag = String::from_utf8_lossy(&line)
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or("*")
+                    .to_string();
+                match verb(&line, 1).as_str() {
+                    "CAPABILITY" => {
+                        say(reader, &format!("* {}\r\n{} OK done\r\n", capability, tag)).await?
+                    }
+                    "NOOP" => say(reader, &format!("{} OK done\r\n", tag)).await?,
+                    "STARTTLS" => {
+                        say(reader, &format!("{} OK Begin TLS negotiation now\r\n", tag)).await?;
+                        return Ok(Preamble {
+                            hello: None,
+                            starttls: line,
+                        });
+                    }
+                    "LOGOUT" => {
+                        say(reader, &format!("* BYE\r\n{} OK done\r\n", tag)).await?;
+                        bail!("client logged out");
+                    }
+                    _ => say(reader, &format!("{} BAD STARTTLS first\r\n", tag)).await?,
+                }
+            }
+        }
+        StartTlsProtocol::Pop3 => {
+            say(reader, &format!("+OK {} ready\r\n", hostname)).await?;
+            loop {
+                let line = read_line(reader).await?;
+                match verb(&line, 0).as_str() {
+                    "CAPA" => say(reader, "+OK\r\nSTLS\r\n.\r\n").await?,
+                    "STLS" => {
+                        say(reader, "+OK Begin TLS negotiation now\r\n").await?;
+                        return Ok(Preamble {
+                            hello: None,
+                            starttls: line,
+                        });
+                    }
+                    "QUIT" => {
+                        say(reader, "+OK Bye\r\n").await?;
+                        bail!("client quit");
+                    }
+                    _ => say(reader, "-ERR STLS first\r\n").await?,
+                }
+            }
+        }
+        StartTlsProtocol::Postgres => loop {
+            let mut request = [0; 8];
+            reader.read_exact(&mut request).await?;
+            match request {
+                PG_SSLREQUEST => {
+                    reader.get_mut().write_all(b"S").await?;
+                    return Ok(Preamble {
+                        hello: None,
+                        starttls: request.to_vec(),
+                    });
+                }
+                // no GSSAPI here, they'll ask for SSL next
+                PG_GSSENCREQUEST => reader.get_mut().write_all(b"N").await?,
+                _ => bail!("client didn't ask for SSL"),
+            }
+        },
+    }
+}
+
+async fn replay_preamble(
+    protocol: StartTlsProtocol,
+    upstream: &mut BufReader<TcpStream>,
+    preamble: &Preamble,
+) -> anyhow::Result<()> {
+    match protocol {
+        StartTlsProtocol::Smtp => {
+            expect_smtp(upstream, "220").await?;
+            if let Some(hello) = &preamble.hello {
+                upstream.get_mut().write_all(hello).await?;
+                expect_smtp(upstream, "250").await?;
+            }
+            upstream.get_mut().write_all(&preamble.starttls).await?;
+            expect_smtp(upstream, "220").await?;
+        }
+        StartTlsProtocol::Imap => {
+            expect_line(upstream, "* OK").await?;
+            upstream.get_mut().write_all(&preamble.starttls).await?;
+            let tag = String::from_utf8_lossy(&preamble.starttls)
+                .split_whitespace()
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            // untagged chatter until our tag comes back
+            loop {
+                let line = read_line(upstream).await?;
+                let line = String::from_utf8_lossy(&line);
+                if let Some(status) = line.strip_prefix(&format!("{} ", tag)) {
+                    if !status.to_ascii_uppercase().starts_with("OK") {
+                        bail!("downstream said {}", line.trim_end());
+                    }
+                    break;
+                }
+            }
+        }
+        StartTlsProtocol::Pop3 => {
+            expect_line(upstream, "+OK").await?;
+            upstream.get_mut().write_all(&preamble.starttls).await?;
+            expect_line(upstream, "+OK").await?;
+        }
+        StartTlsProtocol::Postgres => {
+            upstream.get_mut().write_all(&preamble.starttls).await?;
+            if upstream.read_u8().await? != b'S' {
+                bail!("downstream won't do SSL");
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn expect_line(upstream: &mut BufReader<TcpStream>, prefix: &str) -> anyhow::Result<()> {
+    let line = read_line(upstream).await?;
+    if !line.starts_with(prefix.as_bytes()) {
+        bail!(
+            "downstream said {}",
+            String::from_utf8_lossy(&line).trim_end()
+        );
+    }
+    Ok(())
+}
+
+// multiline replies are "250-..." until the last one, "250 ..."
+async fn expect_smtp(upstream: &mut BufReader<TcpStream>, code: &str) -> anyhow::Result<()> {
+    loop {
+        let line = read_line(upstream).await?;
+        if !line.starts_with(code.as_bytes()) {
+            bail!(
+                "downstream said {}",
+                String::from_utf8_lossy(&line).trim_end()
+            );
+        }
+        if line.get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
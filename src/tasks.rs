@@ -4,15 +4,12 @@ use std::{
     task::{Context, Poll},
     time::Duration,
 };
-use tokio::{io, net::TcpListener, select, task::JoinHandle};
+use tokio::{io, select, task::JoinHandle};
 
 pub async fn listener() -> Result<(), anyhow::Error> {
-    let final_addr = format!(
-        "{}:{}",
-        crate::FULLCFG.listener.addr,
-        crate::FULLCFG.listener.port
-    );
-    let lsnr = TcpListener::bind(&final_addr).await?;
+    let cfg = crate::fullcfg();
+    let final_addr = format!("{}:{}", cfg.listener.addr, cfg.listener.port);
+    let lsnr = crate::sockopt::bind(&final_addr, &cfg.listener).await?;
     tracing::info!("listening on {}", final_addr);
 
     let mut stopper = crate::LISTENER_STOP.1.clone();
@@ -21,8 +18,9 @@ pub async fn listener() -> Result<(), anyhow::Error> {
         _ = stopper.changed() => {tracing::debug!("bailing due to signal received");},
         _ = async {
             loop {
-                let (socket, _) = lsnr.accept().await?;
-                crate::SCONNS.lock().await.spawn(crate::conn::handle_connection(socket));
+                let (socket, client) = lsnr.accept().await?;
+                crate::sockopt::tune_accepted(&socket, &cfg.listener);
+                crate::SCONNS.lock().await.spawn(crate::conn::handle_connection(socket, client));
             }
             #[allow(unreachable_code)]
             Ok::<_, io::Error>(())
@@ -34,6 +32,86 @@ pub async fn listener() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+// the plaintext HTTP port, when [http] has one
+pub async fn http_listener() -> Result<(), anyhow::Error> {
+    let cfg = crate::fullcfg();
+    let Some((http, port)) = cfg
+        .http
+        .as_ref()
+        .and_then(|http| http.port.map(|port| (http, port)))
+    else {
+        return Ok(());
+    };
+    let final_addr = format!(
+        "{}:{}",
+        http.addr.as_deref().unwrap_or(&cfg.listener.addr),
+        port
+    );
+    let lsnr = crate::sockopt::bind(&final_addr, &cfg.listener).await?;
+    tracing::info!("http listening on {}", final_addr);
+
+    let mut stopper = crate::LISTENER_STOP.1.clone();
+    select! {
+        biased;
+        _ = stopper.changed() => {tracing::debug!("http bailing due to signal received");},
+        _ = async {
+            loop {
+                let (socket, client) = lsnr.accept().await?;
+                crate::sockopt::tune_accepted(&socket, &cfg.listener);
+                crate::SCONNS.lock().await.spawn(crate::http::handle_connection(socket, client));
+            }
+            #[allow(unreachable_code)]
+            Ok::<_, io::Error>(())
+        } => {},
+    }
+    Ok(())
+}
+
+// one of these per [starttls.*] section
+pub async fn starttls_listener(name: String) -> Result<(), anyhow::Error> {
+    let cfg = crate::fullcfg();
+    let Some(entry) = cfg
+        .starttls
+        .as_ref()
+        .and_then(|starttls| starttls.get(&name))
+    else {
+        return Ok(());
+    };
+    let final_addr = format!(
+        "{}:{}",
+        entry.addr.as_deref().unwrap_or(&cfg.listener.addr),
+        entry.port
+    );
+    let protocol = entry.protocol;
+    let hostname = entry
+        .hostname
+        .clone()
+        .unwrap_or_else(|| "lurkr".to_string());
+    let lsnr = crate::sockopt::bind(&final_addr, &cfg.listener).await?;
+    tracing::info!(
+        "starttls {} ({:?}) listening on {}",
+        name,
+        protocol,
+        final_addr
+    );
+
+    let mut stopper = crate::LISTENER_STOP.1.clone();
+    select! {
+        biased;
+        _ = stopper.changed() => {tracing::debug!("starttls {} bailing due to signal received", name);},
+        _ = async {
+            loop {
+                let (socket, client) = lsnr.accept().await?;
+                crate::sockopt::tune_accepted(&socket, &cfg.listener);
+                crate::SCONNS.lock().await.spawn(crate::starttls::handle_connection(socket, client, protocol, hostname.clone()));
+            }
+            #[allow(unreachable_code)]
+            Ok::<_, io::Error>(())
+        } => {},
+    }
+    Ok(())
+}
+
 pub async fn connection_terminator() {
     tracing::debug!("terminating connections");
     if !crate::SCONNS.lock().await.is_empty() {
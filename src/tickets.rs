@@ -0,0 +1,215 @@
+// session tickets every lurkr replica can open: the keys come out of a
+// shared secret file, one key per epoch of ticket_rotate_secs wall clock,
+// so same file + roughly the same clock = same keys, no coordination
+//
+// no forward secrecy: every epoch's key comes from that one secret, so
+// whoever gets the file can open every ticket minted with it, past and
+// future. replacing the file (and reloading) is the only thing that
+// retires the old tickets for good
+//
+// ticket: epoch (8, BE) | nonce (12) | AES-256-GCM(state), epoch as AAD
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Error, anyhow};
+use aws_lc_rs::aead::{AES_256_GCM, Aad, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use aws_lc_rs::hkdf::{HKDF_SHA256, Salt};
+use base64::Engine;
+use rustls::server::ProducesTickets;
+
+// six hours, same as rustls's own ticketer rotates
+pub const DEFAULT_ROTATE_SECS: u64 = 6 * 60 * 60;
+const MIN_SECRET_LEN: usize = 32;
+const EPOCH_LEN: usize = 8;
+
+pub struct SharedTicketer {
+    secret: Vec<u8>,
+    rotate_secs: u64,
+}
+
+// the secret stays out of debug output
+impl fmt::Debug for SharedTicketer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedTicketer")
+            .field("rotate_secs", &self.rotate_secs)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SharedTicketer {
+    pub fn from_path(path: &str, rotate_secs: u64) -> Result<SharedTicketer, Error> {
+        let secret =
+            std::fs::read(path).map_err(|err| anyhow!("couldn't read {}: {}", path, err))?;
+        let secret = decode_secret(secret);
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(anyhow!(
+                "{} holds {} bytes of ticket secret, want at least {}",
+                path,
+                secret.len(),
+                MIN_SECRET_LEN
+            ));
+        }
+        if rotate_secs == 0 {
+            return Err(anyhow!("ticket_rotate_secs must be more than 0"));
+        }
+        Ok(SharedTicketer {
+            secret,
+            rotate_secs,
+        })
+    }
+
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / self.rotate_secs
+    }
+
+    fn key(&self, epoch: u64) -> Option<LessSafeKey> {
+        let info = [b"lurkr ticket key".as_slice(), &epoch.to_be_bytes()];
+        let prk = Salt::new(HKDF_SHA256, &[]).extract(&self.secret);
+        let okm = prk.expand(&info, &AES_256_GCM).ok()?;
+        Some(LessSafeKey::new(UnboundKey::from(okm)))
+    }
+
+    fn seal(&self, plain: &[u8], epoch: u64) -> Option<Vec<u8>> {
+        let key = self.key(epoch)?;
+        let mut nonce = [0; NONCE_LEN];
+        aws_lc_rs::rand::fill(&mut nonce).ok()?;
+
+        let mut sealed = plain.to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(epoch.to_be_bytes()),
+            &mut sealed,
+        )
+        .ok()?;
+
+        let mut ticket = Vec::with_capacity(EPOCH_LEN + NONCE_LEN + sealed.len());
+        ticket.extend_from_slice(&epoch.to_be_bytes());
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(&sealed);
+        Some(ticket)
+    }
+
+    fn open(&self, cipher: &[u8], now: u64) -> Option<Vec<u8>> {
+        let (epoch, rest) = cipher.split_first_chunk::<EPOCH_LEN>()?;
+        let (nonce, sealed) = rest.split_first_chunk::<NONCE_LEN>()?;
+        let epoch = u64::from_be_bytes(*epoch);
+        // last epoch's tickets are still good, and a replica whose clock
+        // runs a bit ahead may have minted one from the next
+        if epoch.abs_diff(now) > 1 {
+            return None;
+        }
+        let key = self.key(epoch)?;
+        let mut opened = sealed.to_vec();
+        let plain = key
+            .open_in_place(
+                Nonce::assume_unique_for_key(*nonce),
+                Aad::from(epoch.to_be_bytes()),
+                &mut opened,
+            )
+            .ok()?;
+        Some(plain.to_vec())
+    }
+}
+
+// hex or base64 text (a trailing newline and all) is decoded, anything
+// else is taken byte for byte: trimming a binary secret would lose bytes
+fn decode_secret(file: Vec<u8>) -> Vec<u8> {
+    let text = file.trim_ascii();
+    if !text.is_empty() && text.len().is_multiple_of(2) && text.iter().all(u8::is_ascii_hexdigit) {
+        let nibble = |digit: u8| (digit as char).to_digit(16).unwrap_or_default() as u8;
+        return text
+            .chunks(2)
+            .map(|pair| nibble(pair[0]) << 4 | nibble(pair[1]))
+            .collect();
+    }
+    match base64::engine::general_purpose::STANDARD.decode(text) {
+        Ok(decoded) if !decoded.is_empty() => decoded,
+        _ => file,
+    }
+}
+
+impl ProducesTickets for SharedTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.rotate_secs.try_into().unwrap_or(u32::MAX)
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.seal(plain, self.now())
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.open(cipher, self.now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticketer(secret: &[u8]) -> SharedTicketer {
+        let path = std::env::temp_dir().join(format!("lurkr-ticket-{}", std::process::id()));
+        std::fs::write(&path, secret).unwrap();
+        let ticketer = SharedTicketer::from_path(path.to_str().unwrap(), DEFAULT_ROTATE_SECS);
+        std::fs::remove_file(&path).unwrap();
+        ticketer.unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        let ticketer = ticketer(&[7; 32]);
+        let ticket = ticketer.encrypt(b"session state").unwrap();
+        assert_eq!(ticketer.decrypt(&ticket).unwrap(), b"session state");
+        // another replica, same secret
+        let replica = self::ticketer(&[7; 32]);
+        assert_eq!(replica.decrypt(&ticket).unwrap(), b"session state");
+        assert!(self::ticketer(&[8; 32]).decrypt(&ticket).is_none());
+    }
+
+    #[test]
+    fn takes_neighbouring_epochs_only() {
+        let ticketer = ticketer(&[7; 32]);
+        let ticket = ticketer.seal(b"state", 1000).unwrap();
+        for now in [999, 1000, 1001] {
+            assert_eq!(ticketer.open(&ticket, now).unwrap(), b"state", "{}", now);
+        }
+        for now in [998, 1002, 0] {
+            assert!(ticketer.open(&ticket, now).is_none(), "{}", now);
+        }
+        // the epoch is authenticated: no moving a stale ticket forward
+        let mut moved = ticket.clone();
+        moved[..EPOCH_LEN].copy_from_slice(&1001u64.to_be_bytes());
+        assert!(ticketer.open(&moved, 1001).is_none());
+    }
+
+    #[test]
+    fn refuses_truncated_tickets() {
+        let ticketer = ticketer(&[7; 32]);
+        let ticket = ticketer.seal(b"state", 1000).unwrap();
+        for len in [0, EPOCH_LEN - 1, EPOCH_LEN + NONCE_LEN, ticket.len() - 1] {
+            assert!(ticketer.open(&ticket[..len], 1000).is_none(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn decodes_text_secrets_only() {
+        assert_eq!(decode_secret(b"00ff10\n".to_vec()), [0x00, 0xff, 0x10]);
+        assert_eq!(decode_secret(b"AAECAwQF\n".to_vec()), [0, 1, 2, 3, 4, 5]);
+        // whitespace at the ends of a binary secret is part of it
+        let binary = b"\n\x00\xfe secret\x01 \n".to_vec();
+        assert_eq!(decode_secret(binary.clone()), binary);
+        assert_eq!(
+            ticketer(&[b' '; 40]).secret.len(),
+            40,
+            "all whitespace, still a secret"
+        );
+    }
+}
@@ -1,139 +1,585 @@
-use std::{collections::HashMap, sync::Arc};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use rcgen::generate_simple_self_signed;
-use rustls::crypto::aws_lc_rs::sign::any_supported_type;
-use rustls::{RootCertStore, server::WebPkiClientVerifier};
+use rustls::InconsistentKeys;
+use rustls::crypto::CryptoProvider;
+use rustls::crypto::aws_lc_rs::{self, sign::any_supported_type};
+use rustls::pki_types::CertificateRevocationListDer;
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{NoServerSessionStorage, ServerSessionMemoryCache, WebPkiClientVerifier};
+use rustls::sign::{CertifiedKey, SigningKey};
+use rustls::{RootCertStore, SupportedProtocolVersion};
 
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
 
 use rustls_pki_types::pem::PemObject;
 use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, X509Certificate};
 
 use anyhow::{Error, Result, anyhow};
+use p12_keystore::KeyStore;
+use pkcs8::EncryptedPrivateKeyInfo;
 
-use crate::conf::TlsConfigEntry;
+use crate::TlsMap;
+use crate::conf::{Configuration, CrlDepth, CrlUnknownStatus, Diagnostics, TlsConfigEntry};
+use crate::crl::{self, ReloadingVerifier};
+use crate::expiry::{Role, Watched};
+use crate::ocsp::{self, OcspSpec, StapledCert};
+use crate::tickets::{self, SharedTicketer};
 
-pub fn acceptors_from_configuration() -> anyhow::Result<HashMap<String, Arc<TlsAcceptor>>, Error> {
-    let mut tlses = HashMap::<String, Arc<TlsAcceptor>>::new();
+// every TLS config that builds ends up in the map, its certs in the list
+// the ones that don't get reported by name and left out
+pub fn acceptors_from_configuration(
+    cfg: &Configuration,
+    diag: &mut Diagnostics,
+) -> (TlsMap, Vec<Watched>) {
+    let mut tlses = TlsMap::new();
+    let mut watched = vec![];
     // if-present, iterate over config-present tls specification sections
-    if let Some(tlscfgs) = &crate::FULLCFG.tls {
+    if let Some(tlscfgs) = &cfg.tls {
         for (tlsname, tlsspec) in tlscfgs.iter() {
             log::debug!("building tlsspec {}", tlsname);
+            let mut warnings = vec![];
+            let mut certs = vec![];
+            match acceptor_from_tlsspec(tlsspec, &mut warnings, &mut certs) {
+                Ok(acceptor) => {
+                    tlses.insert(tlsname.clone(), Arc::new(acceptor));
+                    watched.extend(certs.into_iter().map(|cert| Watched {
+                        tls: tlsname.clone(),
+                        ..cert
+                    }));
+                }
+                Err(err) => diag.error(format!("tls config {}: {:#}", tlsname, err)),
+            }
+            for warning in warnings {
+                diag.warn(format!("tls config {}: {}", tlsname, warning));
+            }
+        }
+    }
+    (tlses, watched)
+}
 
-            let to_generate = tlsspec.certs.is_none() && tlsspec.certs_path.is_none();
-            let identity_key: PrivateKeyDer<'static>;
-            let identity_certs: Vec<CertificateDer<'static>>;
-            if to_generate {
-                let ck = generate_simple_self_signed(vec!["localhost".to_string()])?;
-                identity_certs = vec![ck.cert.der().to_owned()];
-                identity_key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(Vec::from(
-                    ck.signing_key.serialized_der(),
-                )));
-            } else {
-                identity_key = load_key_from_tlsspec(&tlsspec)?;
-                identity_certs = server_certificates(&tlsspec)?;
+// warnings: things that work but probably aren't what anyone wants
+// watched: the loaded certs, for keeping an eye on their expiry
+pub fn acceptor_from_tlsspec(
+    tlsspec: &TlsConfigEntry,
+    warnings: &mut Vec<String>,
+    watched: &mut Vec<Watched>,
+) -> Result<TlsAcceptor, Error> {
+    let to_generate = certs_source(tlsspec)?.is_none() && tlsspec.pkcs12_path.is_none();
+    let identity_key: PrivateKeyDer<'static>;
+    let identity_certs: Vec<CertificateDer<'static>>;
+    if to_generate {
+        let ck = generate_simple_self_signed(vec!["localhost".to_string()])?;
+        identity_certs = vec![ck.cert.der().to_owned()];
+        identity_key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(Vec::from(
+            ck.signing_key.serialized_der(),
+        )));
+    } else if let Some(pkcs12_path) = &tlsspec.pkcs12_path {
+        (identity_key, identity_certs) = load_pkcs12(tlsspec, pkcs12_path)?;
+    } else {
+        identity_key = load_key_from_tlsspec(tlsspec)?;
+        identity_certs = server_certificates(tlsspec)?;
+    }
+
+    let wants_ocsp = tlsspec.ocsp_path.is_some()
+        || tlsspec.ocsp_fetch == Some(true)
+        || tlsspec.ocsp_responder.is_some();
+    if to_generate && wants_ocsp {
+        return Err(anyhow!("ocsp stapling needs certs, not a generated one"));
+    }
+    if identity_certs.is_empty() {
+        return Err(anyhow!("no certificates in {}", identity_whence(tlsspec)?));
+    }
+
+    // to make sure it explodes if unsupported
+    let signing_key =
+        any_supported_type(&identity_key).map_err(|err| anyhow!("unsupported key: {}", err))?;
+    if !to_generate {
+        let whence = identity_whence(tlsspec)?;
+        sanity_check(&identity_certs, signing_key, &whence, warnings)?;
+        watched.extend(Watched::from_certs(&identity_certs, Role::Server, &whence));
+    }
+
+    // Client auth certificates
+    let is_clientrequested = client_bundle_source(tlsspec)?.is_some();
+    let ccfgcerts = client_certificates(tlsspec)?;
+    if let Some(source) = client_bundle_source(tlsspec)? {
+        watched.extend(Watched::from_certs(
+            &ccfgcerts,
+            Role::ClientCa,
+            &source.to_string(),
+        ));
+    }
+
+    let client_auth = if is_clientrequested {
+        let mut roots = RootCertStore::empty();
+        let (_, ignored) = roots.add_parsable_certificates(ccfgcerts);
+        if ignored > 0 {
+            warnings.push(format!(
+                "{} certificate(s) in the client cert bundle can't be trust anchors, ignored",
+                ignored
+            ));
+        }
+        if roots.is_empty() {
+            log::debug!("requested client auth with empty trust roots. sus");
+            // lmao rustls is trying to save us from ourselves
+            // but guess what we WANT to be stupid sometimes
+            roots.add(
+                generate_simple_self_signed(vec!["example.com".into()])?
+                    .cert
+                    .der()
+                    .to_owned(),
+            )?;
+        }
+        let roots = Arc::new(roots);
+        let crls = if crl::wanted(tlsspec) {
+            crl::load(tlsspec)?
+        } else {
+            vec![]
+        };
+        let verifier = client_verifier(tlsspec, roots.clone(), crls)?;
+        if tlsspec.client_crl_path.is_some() {
+            let reloading = Arc::new(ReloadingVerifier::new(verifier));
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                runtime.spawn(crl::keep_current(
+                    Arc::downgrade(&reloading),
+                    tlsspec.clone(),
+                    roots,
+                ));
             }
+            reloading
+        } else {
+            verifier
+        }
+    } else if crl::wanted(tlsspec) {
+        return Err(anyhow!("client CRLs without a client_certbundle to check"));
+    } else {
+        WebPkiClientVerifier::no_client_auth()
+    };
 
-            if identity_certs.is_empty() {
-                panic!("missing workable entry for tls config (missing certs)");
+    let provider = crypto_provider(tlsspec)?;
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&protocol_versions(tlsspec)?)?
+        .with_client_cert_verifier(client_auth);
+    let ocsp = if to_generate {
+        None
+    } else {
+        OcspSpec::from_tlsspec(tlsspec, &identity_certs)?
+    };
+    let mut tls_config = match ocsp {
+        None => builder.with_single_cert(identity_certs, identity_key.clone_key())?,
+        Some(spec) => {
+            let mut certified =
+                CertifiedKey::from_der(identity_certs, identity_key.clone_key(), &provider)?;
+            let stapled = spec.obtain_now()?;
+            let next_update = stapled.as_ref().and_then(|(_, next_update)| *next_update);
+            certified.ocsp = stapled.map(|(response, _)| response);
+            let resolver = Arc::new(StapledCert::new(certified));
+            // no runtime, no refreshing: nobody's being served anyway
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                runtime.spawn(ocsp::keep_stapled(
+                    Arc::downgrade(&resolver),
+                    spec,
+                    next_update,
+                ));
             }
+            builder.with_cert_resolver(resolver)
+        }
+    };
+    if let Some(alpn) = &tlsspec.alpn {
+        tls_config.alpn_protocols = alpn.iter().map(|proto| proto.as_bytes().to_vec()).collect();
+    }
+    if let Some(size) = tlsspec.session_cache_size {
+        tls_config.session_storage = if size == 0 {
+            Arc::new(NoServerSessionStorage {})
+        } else {
+            ServerSessionMemoryCache::new(size)
+        };
+    }
+    if let Some(ticket_key_path) = &tlsspec.ticket_key_path {
+        let rotate_secs = tlsspec
+            .ticket_rotate_secs
+            .unwrap_or(tickets::DEFAULT_ROTATE_SECS);
+        tls_config.ticketer = Arc::new(SharedTicketer::from_path(ticket_key_path, rotate_secs)?);
+    } else if tlsspec.session_tickets == Some(true) {
+        tls_config.ticketer = aws_lc_rs::Ticketer::new()?;
+    }
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
 
-            // to make sure it explodes if unsupported
-            let _signing_key = any_supported_type(&identity_key).expect("unsupported key");
-
-            // Client auth certificates
-            let is_clientrequested =
-                tlsspec.client_certbundle.is_some() || tlsspec.client_certbundle_path.is_some();
-            let ccfgcerts = client_certificates(&tlsspec)?;
-
-            let client_auth = if is_clientrequested {
-                let mut roots = RootCertStore::empty();
-                roots.add_parsable_certificates(ccfgcerts);
-                if roots.is_empty() {
-                    log::debug!("requested client auth with empty trust roots. sus");
-                    // lmao rustls is trying to save us from ourselves
-                    // but guess what we WANT to be stupid sometimes
-                    roots.add(
-                        generate_simple_self_signed(vec!["example.com".into()])?
-                            .cert
-                            .der()
-                            .to_owned(),
-                    )?;
-                }
-                if tlsspec.require_client_auth == Some(true) {
-                    // only allow proven connections
-                    WebPkiClientVerifier::builder(roots.into()).build()?
-                } else {
-                    WebPkiClientVerifier::builder(roots.into())
-                        .allow_unauthenticated()
-                        .build()?
-                }
-            } else {
-                WebPkiClientVerifier::no_client_auth()
-            };
-
-            let tls_config = rustls::ServerConfig::builder()
-                .with_client_cert_verifier(client_auth)
-                .with_single_cert(identity_certs, identity_key.clone_key())?;
-            tlses.insert(
-                tlsname.clone(),
-                Arc::new(TlsAcceptor::from(Arc::new(tls_config))),
-            );
+pub(crate) fn client_verifier(
+    tlsspec: &TlsConfigEntry,
+    roots: Arc<RootCertStore>,
+    crls: Vec<CertificateRevocationListDer<'static>>,
+) -> Result<Arc<dyn ClientCertVerifier>, Error> {
+    let revocation = !crls.is_empty();
+    let mut builder = WebPkiClientVerifier::builder(roots).with_crls(crls);
+    if tlsspec.require_client_auth != Some(true) {
+        builder = builder.allow_unauthenticated();
+    }
+    if revocation {
+        if tlsspec.crl_depth == Some(CrlDepth::EndEntity) {
+            builder = builder.only_check_end_entity_revocation();
+        }
+        if tlsspec.crl_unknown_status == Some(CrlUnknownStatus::Allow) {
+            builder = builder.allow_unknown_revocation_status();
         }
+        if tlsspec.crl_enforce_expiration == Some(true) {
+            builder = builder.enforce_revocation_expiration();
+        }
+    }
+    Ok(builder.build()?)
+}
+
+fn protocol_versions(
+    tlsspec: &TlsConfigEntry,
+) -> Result<Vec<&'static SupportedProtocolVersion>, Error> {
+    let Some(versions) = &tlsspec.versions else {
+        return Ok(rustls::DEFAULT_VERSIONS.to_vec());
+    };
+    let versions = versions
+        .iter()
+        .map(|version| match version.trim_start_matches("TLS").trim() {
+            "1.2" => Ok(&rustls::version::TLS12),
+            "1.3" => Ok(&rustls::version::TLS13),
+            _ => Err(anyhow!(
+                "unsupported tls version {:?} (1.2 or 1.3)",
+                version
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if versions.is_empty() {
+        return Err(anyhow!("versions is empty"));
     }
-    Ok(tlses)
+    Ok(versions)
+}
+
+// the aws-lc-rs provider, whittled down to what the spec lists
+// order in the config is the server's preference order
+fn crypto_provider(tlsspec: &TlsConfigEntry) -> Result<Arc<CryptoProvider>, Error> {
+    let mut provider = aws_lc_rs::default_provider();
+    if let Some(names) = &tlsspec.cipher_suites {
+        provider.cipher_suites = pick(
+            names,
+            aws_lc_rs::ALL_CIPHER_SUITES,
+            "cipher suite",
+            |suite| format!("{:?}", suite.suite()),
+        )?;
+    }
+    if let Some(names) = &tlsspec.kx_groups {
+        provider.kx_groups = pick(names, aws_lc_rs::ALL_KX_GROUPS, "kx group", |group| {
+            format!("{:?}", group.name())
+        })?;
+    }
+    Ok(Arc::new(provider))
+}
+
+fn pick<T: Copy>(
+    names: &[String],
+    available: &[T],
+    what: &str,
+    name_of: impl Fn(&T) -> String,
+) -> Result<Vec<T>, Error> {
+    if names.is_empty() {
+        return Err(anyhow!("empty {} list", what));
+    }
+    names
+        .iter()
+        .map(|name| {
+            available
+                .iter()
+                .find(|item| name_of(item).eq_ignore_ascii_case(name))
+                .copied()
+                .ok_or_else(|| {
+                    let known: Vec<String> = available.iter().map(&name_of).collect();
+                    anyhow!(
+                        "unknown {} {:?}, try one of {}",
+                        what,
+                        name,
+                        known.join(", ")
+                    )
+                })
+        })
+        .collect()
 }
 
 pub fn load_key_from_tlsspec(tlsspec: &TlsConfigEntry) -> Result<PrivateKeyDer<'static>, Error> {
-    if let Some(key) = &tlsspec.key {
-        log::debug!("loading key from literal");
-        Ok(PrivateKeyDer::from_pem_slice(key.as_bytes())?.clone_key())
-    } else if let Some(key_path) = &tlsspec.key_path {
-        log::debug!("loading key from file");
-        Ok(PrivateKeyDer::from_pem_file(key_path)?.clone_key())
-    } else {
-        Err(anyhow!("tls spec did not fill out either key type"))
+    let Some(source) = key_source(tlsspec)? else {
+        return Err(anyhow!("tls spec did not fill out any key source"));
+    };
+    log::debug!("loading key from {}", source);
+    let pem = source.read()?;
+    let whence = source.to_string();
+    // password-protected PKCS#8 has a PEM label of its own
+    let encrypted = pem::parse_many(&pem)
+        .unwrap_or_default()
+        .into_iter()
+        .find(|block| block.tag() == "ENCRYPTED PRIVATE KEY");
+    if let Some(block) = encrypted {
+        let password = key_password(tlsspec)?.ok_or_else(|| {
+            anyhow!(
+                "{} is encrypted, set key_password_env or key_password_path",
+                whence
+            )
+        })?;
+        let decrypted = EncryptedPrivateKeyInfo::try_from(block.contents())
+            .and_then(|info| info.decrypt(&password))
+            .map_err(|err| anyhow!("couldn't decrypt {}: {}", whence, err))?;
+        return Ok(PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+            decrypted.as_bytes().to_vec(),
+        )));
     }
+    Ok(PrivateKeyDer::from_pem_slice(&pem)
+        .map_err(|err| anyhow!("no usable key in {}: {}", whence, err))?
+        .clone_key())
+}
+
+// from the environment or a file, never the config itself
+fn key_password(tlsspec: &TlsConfigEntry) -> Result<Option<String>, Error> {
+    match (&tlsspec.key_password_env, &tlsspec.key_password_path) {
+        (Some(_), Some(_)) => Err(anyhow!("key_password_env or key_password_path, not both")),
+        (Some(var), None) => std::env::var(var)
+            .map(Some)
+            .map_err(|err| anyhow!("key_password_env {}: {}", var, err)),
+        (None, Some(path)) => {
+            let password = std::fs::read_to_string(path)
+                .map_err(|err| anyhow!("couldn't read {}: {}", path, err))?;
+            // editors and `echo` leave a newline, nobody means it
+            Ok(Some(password.trim_end_matches(['\r', '\n']).to_string()))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+// key, leaf and whatever chain the bundle carries, leaf first
+fn load_pkcs12(
+    tlsspec: &TlsConfigEntry,
+    pkcs12_path: &str,
+) -> Result<(PrivateKeyDer<'static>, Vec<CertificateDer<'static>>), Error> {
+    // only what's spelled out; secret_dir may well hold a tls.key too
+    let explicit = [
+        &tlsspec.key,
+        &tlsspec.key_env,
+        &tlsspec.key_path,
+        &tlsspec.certs,
+        &tlsspec.certs_env,
+        &tlsspec.certs_path,
+    ];
+    if explicit.iter().any(|field| field.is_some()) {
+        return Err(anyhow!(
+            "pkcs12_path brings its own key and certs, drop key/certs"
+        ));
+    }
+    let bundle = std::fs::read(pkcs12_path)
+        .map_err(|err| anyhow!("couldn't read {}: {}", pkcs12_path, err))?;
+    let password = key_password(tlsspec)?.unwrap_or_default();
+    let keystore = KeyStore::from_pkcs12(&bundle, &password)
+        .map_err(|err| anyhow!("couldn't open {}: {}", pkcs12_path, err))?;
+    let Some((alias, chain)) = keystore.private_key_chain() else {
+        return Err(anyhow!("{} has no private key in it", pkcs12_path));
+    };
+    log::debug!("using {} from {}", alias, pkcs12_path);
+    Ok((
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(chain.key().to_vec())),
+        chain
+            .chain()
+            .iter()
+            .map(|cert| CertificateDer::from(cert.as_der().to_vec()))
+            .collect(),
+    ))
 }
 
 pub fn server_certificates(
     tlsspec: &TlsConfigEntry,
 ) -> Result<Vec<CertificateDer<'static>>, Error> {
-    if let Some(certliteral) = &tlsspec.certs {
-        log::debug!("NI: loading certs from literal");
-        Ok(CertificateDer::pem_slice_iter(certliteral.as_bytes())
-            .map(|cert| cert.unwrap().into_owned())
-            .collect())
-    } else if let Some(certs_path) = &tlsspec.certs_path {
-        // load certs from file
-        log::debug!("loading certs from file");
-        Ok(CertificateDer::pem_file_iter(certs_path)
-            .unwrap()
-            .map(|cert| cert.unwrap().into_owned())
-            .collect())
-    } else {
-        Ok(vec![])
+    let Some(source) = certs_source(tlsspec)? else {
+        return Ok(vec![]);
+    };
+    log::debug!("loading certs from {}", source);
+    parse_certificates(&source.read()?, &source)
+}
+
+pub fn client_certificates(
+    tlsspec: &TlsConfigEntry,
+) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let Some(source) = client_bundle_source(tlsspec)? else {
+        log::debug!("no client file or literal: okay");
+        return Ok(vec![]);
+    };
+    if let PemSource::File(path) = &source
+        && path.as_os_str().is_empty()
+    {
+        log::debug!("emptypath skip");
+        return Ok(vec![]);
     }
+    log::debug!("loading client cert trust bundle from {}", source);
+    parse_certificates(&source.read()?, &source)
+}
+
+// every CERTIFICATE block, numbered from 1 when one of them is bad
+fn parse_certificates(
+    pem: &[u8],
+    source: &PemSource<'_>,
+) -> Result<Vec<CertificateDer<'static>>, Error> {
+    CertificateDer::pem_slice_iter(pem)
+        .enumerate()
+        .map(|(idx, cert)| {
+            let cert = cert.map_err(|err| {
+                anyhow!(
+                    "{}: PEM block for certificate #{}: {}",
+                    source,
+                    idx + 1,
+                    err
+                )
+            })?;
+            X509Certificate::from_der(&cert).map_err(|err| {
+                anyhow!("{}: certificate #{} isn't X.509: {}", source, idx + 1, err)
+            })?;
+            Ok(cert.into_owned())
+        })
+        .collect()
 }
 
-pub fn client_certificates(tlsspec: &TlsConfigEntry) -> Result<Vec<CertificateDer<'_>>, Error> {
-    if let Some(inlinebundle) = &tlsspec.client_certbundle {
-        log::debug!("loading client cert trust bundle from literal");
-        Ok(CertificateDer::pem_slice_iter(inlinebundle.as_bytes())
-            .map(|cert| cert.unwrap())
-            .collect())
-    } else if let Some(certbundle_path) = &tlsspec.client_certbundle_path {
-        if certbundle_path.is_empty() {
-            log::debug!("emptypath skip");
-            return Ok(vec![]);
+// where the served certs came from, for complaining about them
+fn identity_whence(tlsspec: &TlsConfigEntry) -> Result<String, Error> {
+    Ok(match (&tlsspec.pkcs12_path, certs_source(tlsspec)?) {
+        (Some(pkcs12_path), _) => pkcs12_path.clone(),
+        (None, Some(source)) => source.to_string(),
+        (None, None) => "generated".to_string(),
+    })
+}
+
+// what would otherwise only turn up as failed handshakes or angry
+// users: the wrong key, a scrambled chain; expiry::assess does dates
+fn sanity_check(
+    certs: &[CertificateDer<'static>],
+    signing_key: Arc<dyn SigningKey>,
+    whence: &str,
+    warnings: &mut Vec<String>,
+) -> Result<(), Error> {
+    if let Err(rustls::Error::InconsistentKeys(InconsistentKeys::KeyMismatch)) =
+        CertifiedKey::new(certs.to_vec(), signing_key).keys_match()
+    {
+        return Err(anyhow!(
+            "the key doesn't belong to the first certificate in {} (the leaf goes first)",
+            whence
+        ));
+    }
+
+    // parse_certificates already vouched for these
+    let parsed: Vec<X509Certificate<'_>> = certs
+        .iter()
+        .filter_map(|cert| X509Certificate::from_der(cert).ok().map(|(_, cert)| cert))
+        .collect();
+    for (idx, pair) in parsed.windows(2).enumerate() {
+        if pair[0].issuer().as_raw() != pair[1].subject().as_raw() {
+            warnings.push(format!(
+                "{}: certificate #{} ({}) isn't the issuer of #{} ({}), want leaf first and then each issuer",
+                whence,
+                idx + 2,
+                pair[1].subject(),
+                idx + 1,
+                pair[0].subject()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// where a piece of PEM lives: in the config, an environment variable
+// (so secrets stay out of TOML) or a file
+pub enum PemSource<'a> {
+    Literal(&'a str, &'static str),
+    Env(&'a str),
+    File(PathBuf),
+}
+
+impl PemSource<'_> {
+    // one of literal/env/path at most; default is the secret_dir file
+    fn pick<'a>(
+        field: &'static str,
+        literal: &'a Option<String>,
+        env: &'a Option<String>,
+        path: &'a Option<String>,
+        default: Option<PathBuf>,
+    ) -> Result<Option<PemSource<'a>>, Error> {
+        let given = [literal.is_some(), env.is_some(), path.is_some()];
+        if given.iter().filter(|given| **given).count() > 1 {
+            return Err(anyhow!(
+                "{0}, {0}_env and {0}_path are either/or, more than one is set",
+                field
+            ));
+        }
+        Ok(if let Some(literal) = literal {
+            Some(PemSource::Literal(literal, field))
+        } else if let Some(env) = env {
+            Some(PemSource::Env(env))
+        } else if let Some(path) = path {
+            Some(PemSource::File(PathBuf::from(path)))
+        } else {
+            default.map(PemSource::File)
+        })
+    }
+
+    pub fn read(&self) -> Result<Vec<u8>, Error> {
+        match self {
+            PemSource::Literal(literal, _) => Ok(literal.as_bytes().to_vec()),
+            PemSource::Env(var) => std::env::var(var)
+                .map(String::into_bytes)
+                .map_err(|err| anyhow!("${}: {}", var, err)),
+            PemSource::File(path) => std::fs::read(path)
+                .map_err(|err| anyhow!("couldn't read {}: {}", path.display(), err)),
+        }
+    }
+}
+
+impl fmt::Display for PemSource<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PemSource::Literal(_, field) => write!(f, "{} literal", field),
+            PemSource::Env(var) => write!(f, "${}", var),
+            PemSource::File(path) => write!(f, "{}", path.display()),
         }
-        log::debug!("loading client cert trust bundle from file");
-        Ok(CertificateDer::pem_file_iter(certbundle_path)
-            .unwrap()
-            .map(|cert| cert.unwrap())
-            .collect())
-    } else {
-        log::debug!("no client file or literal: okay");
-        Ok(vec![])
     }
 }
+
+// a kubernetes.io/tls secret, mounted: tls.crt and tls.key
+fn in_secret_dir(tlsspec: &TlsConfigEntry, name: &str) -> Option<PathBuf> {
+    tlsspec
+        .secret_dir
+        .as_ref()
+        .map(|dir| Path::new(dir).join(name))
+}
+
+pub fn key_source(tlsspec: &TlsConfigEntry) -> Result<Option<PemSource<'_>>, Error> {
+    PemSource::pick(
+        "key",
+        &tlsspec.key,
+        &tlsspec.key_env,
+        &tlsspec.key_path,
+        in_secret_dir(tlsspec, "tls.key"),
+    )
+}
+
+pub fn certs_source(tlsspec: &TlsConfigEntry) -> Result<Option<PemSource<'_>>, Error> {
+    PemSource::pick(
+        "certs",
+        &tlsspec.certs,
+        &tlsspec.certs_env,
+        &tlsspec.certs_path,
+        in_secret_dir(tlsspec, "tls.crt"),
+    )
+}
+
+pub fn client_bundle_source(tlsspec: &TlsConfigEntry) -> Result<Option<PemSource<'_>>, Error> {
+    PemSource::pick(
+        "client_certbundle",
+        &tlsspec.client_certbundle,
+        &tlsspec.client_certbundle_env,
+        &tlsspec.client_certbundle_path,
+        None,
+    )
+}
@@ -0,0 +1,200 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    ops::Deref,
+    pin::Pin,
+    sync::{
+        Arc, LazyLock, Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_derive::Serialize;
+
+use crate::fingerprint::Fingerprint;
+use tokio::{
+    io::{self, AsyncRead, ReadBuf},
+    net::TcpStream,
+};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// everything in flight, for the admin API to gawk at
+pub static ACTIVE: LazyLock<Mutex<BTreeMap<u64, Arc<ConnInfo>>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+// downstream address to how connecting to it has been going
+pub static DOWNSTREAMS: LazyLock<Mutex<HashMap<String, DownstreamHealth>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug)]
+pub struct ConnInfo {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub started: Instant,
+    // these get filled in as the connection figures itself out
+    pub sni: OnceLock<String>,
+    pub rule: OnceLock<String>,
+    pub downstream: OnceLock<String>,
+    pub fingerprint: OnceLock<Fingerprint>,
+    // client to downstream, and back
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConnSnapshot {
+    pub id: u64,
+    pub client: SocketAddr,
+    pub sni: Option<String>,
+    pub rule: Option<String>,
+    pub downstream: Option<String>,
+    pub ja3: Option<String>,
+    pub ja4: Option<String>,
+    pub age_secs: f64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+impl ConnInfo {
+    pub fn snapshot(&self) -> ConnSnapshot {
+        ConnSnapshot {
+            id: self.id,
+            client: self.client,
+            sni: self.sni.get().cloned(),
+            rule: self.rule.get().cloned(),
+            downstream: self.downstream.get().cloned(),
+            ja3: self.fingerprint.get().map(|fp| fp.ja3.clone()),
+            ja4: self.fingerprint.get().map(|fp| fp.ja4.clone()),
+            age_secs: self.started.elapsed().as_secs_f64(),
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// registered for as long as this lives
+pub struct Tracked(Arc<ConnInfo>);
+
+impl Tracked {
+    pub fn new(client: SocketAddr) -> Tracked {
+        let info = Arc::new(ConnInfo {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            client,
+            started: Instant::now(),
+            sni: OnceLock::new(),
+            rule: OnceLock::new(),
+            downstream: OnceLock::new(),
+            fingerprint: OnceLock::new(),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+        });
+        ACTIVE.lock().unwrap().insert(info.id, info.clone());
+        Tracked(info)
+    }
+}
+
+impl Deref for Tracked {
+    type Target = ConnInfo;
+    fn deref(&self) -> &ConnInfo {
+        &self.0
+    }
+}
+
+// one access log line per connection, on the way out
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        ACTIVE.lock().unwrap().remove(&self.0.id);
+        let conn = &self.0;
+        let dash = String::from("-");
+        tracing::info!(
+            target: "lurkr::access",
+            "{} {} sni={} rule={} downstream={} ja3={} ja4={} up={} down={} {:.3}s",
+            conn.id,
+            conn.client,
+            conn.sni.get().unwrap_or(&dash),
+            conn.rule.get().unwrap_or(&dash),
+            conn.downstream.get().unwrap_or(&dash),
+            conn.fingerprint.get().map_or("-", |fp| &fp.ja3),
+            conn.fingerprint.get().map_or("-", |fp| &fp.ja4),
+            conn.bytes_up.load(Ordering::Relaxed),
+            conn.bytes_down.load(Ordering::Relaxed),
+            conn.started.elapsed().as_secs_f64(),
+        );
+    }
+}
+
+pub fn snapshot() -> Vec<ConnSnapshot> {
+    ACTIVE
+        .lock()
+        .unwrap()
+        .values()
+        .map(|conn| conn.snapshot())
+        .collect()
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct DownstreamHealth {
+    pub connects_ok: u64,
+    pub connects_failed: u64,
+    // unix seconds
+    pub last_ok: Option<u64>,
+    pub last_failure: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// connect, and remember whether it worked
+pub async fn connect_downstream(addr: &str) -> io::Result<TcpStream> {
+    let result = crate::sockopt::connect(addr).await;
+    let mut downstreams = DOWNSTREAMS.lock().unwrap();
+    let health = downstreams.entry(addr.to_string()).or_default();
+    match &result {
+        Ok(_) => {
+            health.connects_ok += 1;
+            health.last_ok = Some(unix_now());
+        }
+        Err(err) => {
+            health.connects_failed += 1;
+            health.last_failure = Some(unix_now());
+            health.last_error = Some(err.to_string());
+        }
+    }
+    result
+}
+
+// AsyncRead that tallies what went through it
+pub struct Counted<'a, R> {
+    inner: R,
+    counter: &'a AtomicU64,
+}
+
+impl<'a, R> Counted<'a, R> {
+    pub fn new(inner: R, counter: &'a AtomicU64) -> Self {
+        Self { inner, counter }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Counted<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = polled {
+            self.counter
+                .fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        }
+        polled
+    }
+}
@@ -0,0 +1,89 @@
+# regenerates the CRL fixtures the tests in src/crl.rs check against:
+#   python3 generate.py   (needs the "cryptography" package)
+# a throwaway CA, two client certs, and CRLs from that CA with fixed
+# times: one revoking the first client, one revoking nobody
+
+import datetime
+
+from cryptography import x509
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import ec
+from cryptography.x509.oid import ExtendedKeyUsageOID, NameOID
+
+UTC = datetime.timezone.utc
+LAST_UPDATE = datetime.datetime(2026, 10, 1, tzinfo=UTC)
+NEXT_UPDATE = datetime.datetime(2036, 10, 8, tzinfo=UTC)
+
+
+def name(cn):
+    return x509.Name([x509.NameAttribute(NameOID.COMMON_NAME, cn)])
+
+
+def key_usage(ca):
+    return x509.KeyUsage(
+        digital_signature=not ca,
+        content_commitment=False,
+        key_encipherment=False,
+        data_encipherment=False,
+        key_agreement=False,
+        key_cert_sign=ca,
+        crl_sign=ca,
+        encipher_only=False,
+        decipher_only=False,
+    )
+
+
+def cert(subject, key, serial, ca):
+    builder = (
+        x509.CertificateBuilder()
+        .subject_name(name(subject))
+        .issuer_name(name("lurkr test crl ca"))
+        .public_key(key.public_key())
+        .serial_number(serial)
+        .not_valid_before(datetime.datetime(2026, 1, 1, tzinfo=UTC))
+        .not_valid_after(datetime.datetime(2046, 1, 1, tzinfo=UTC))
+        .add_extension(x509.BasicConstraints(ca=ca, path_length=None), critical=True)
+        .add_extension(key_usage(ca), critical=True)
+    )
+    if not ca:
+        builder = builder.add_extension(
+            x509.ExtendedKeyUsage([ExtendedKeyUsageOID.CLIENT_AUTH]), critical=False
+        )
+    return builder.sign(ca_key, hashes.SHA256())
+
+
+def crl(number, revoked):
+    builder = (
+        x509.CertificateRevocationListBuilder()
+        .issuer_name(name("lurkr test crl ca"))
+        .last_update(LAST_UPDATE)
+        .next_update(NEXT_UPDATE)
+        .add_extension(x509.CRLNumber(number), critical=False)
+    )
+    for serial in revoked:
+        builder = builder.add_revoked_certificate(
+            x509.RevokedCertificateBuilder()
+            .serial_number(serial)
+            .revocation_date(LAST_UPDATE)
+            .build()
+        )
+    return builder.sign(ca_key, hashes.SHA256())
+
+
+ca_key = ec.generate_private_key(ec.SECP256R1())
+client_key = ec.generate_private_key(ec.SECP256R1())
+ca = cert("lurkr test crl ca", ca_key, 0x1001, True)
+revoked = cert("revoked.client.example", client_key, 0x2002, False)
+good = cert("good.client.example", client_key, 0x3003, False)
+
+fixtures = {
+    "ca.der": ca.public_bytes(serialization.Encoding.DER),
+    "revoked-client.der": revoked.public_bytes(serialization.Encoding.DER),
+    "good-client.der": good.public_bytes(serialization.Encoding.DER),
+    # PEM, the way client_crl_path usually gets it
+    "revoking.pem": crl(2, [0x2002]).public_bytes(serialization.Encoding.PEM),
+    "empty.pem": crl(1, []).public_bytes(serialization.Encoding.PEM),
+}
+for path, data in fixtures.items():
+    with open(path, "wb") as out:
+        out.write(data)
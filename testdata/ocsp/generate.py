@@ -0,0 +1,81 @@
+# regenerates the OCSP fixtures the tests in src/ocsp.rs decode:
+#   python3 generate.py   (needs the "cryptography" package)
+# a throwaway CA and leaf, and responses signed by that CA with fixed
+# times, so the expected nextUpdate values never move
+
+import datetime
+
+from cryptography import x509
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import ec
+from cryptography.x509 import ocsp
+from cryptography.x509.oid import NameOID
+
+UTC = datetime.timezone.utc
+THIS_UPDATE = datetime.datetime(2026, 10, 1, tzinfo=UTC)
+NEXT_UPDATE = datetime.datetime(2036, 10, 8, tzinfo=UTC)
+EXPIRED_THIS = datetime.datetime(2020, 1, 1, tzinfo=UTC)
+EXPIRED_NEXT = datetime.datetime(2020, 1, 8, 12, 30, 15, tzinfo=UTC)
+
+
+def name(cn):
+    return x509.Name([x509.NameAttribute(NameOID.COMMON_NAME, cn)])
+
+
+def cert(subject, issuer, key, signer, serial, ca):
+    return (
+        x509.CertificateBuilder()
+        .subject_name(name(subject))
+        .issuer_name(name(issuer))
+        .public_key(key.public_key())
+        .serial_number(serial)
+        .not_valid_before(datetime.datetime(2026, 1, 1, tzinfo=UTC))
+        .not_valid_after(datetime.datetime(2046, 1, 1, tzinfo=UTC))
+        .add_extension(x509.BasicConstraints(ca=ca, path_length=None), critical=True)
+        .sign(signer, hashes.SHA256())
+    )
+
+
+ca_key = ec.generate_private_key(ec.SECP256R1())
+leaf_key = ec.generate_private_key(ec.SECP256R1())
+ca = cert("lurkr test ocsp ca", "lurkr test ocsp ca", ca_key, ca_key, 0x1001, True)
+leaf = cert("ocsp.example", "lurkr test ocsp ca", leaf_key, ca_key, 0x2002, False)
+other = cert("other.example", "lurkr test ocsp ca", leaf_key, ca_key, 0x3003, False)
+
+
+def response(subject, status, this_update, next_update, revoked_at=None):
+    builder = ocsp.OCSPResponseBuilder().add_response(
+        cert=subject,
+        issuer=ca,
+        algorithm=hashes.SHA1(),
+        cert_status=status,
+        this_update=this_update,
+        next_update=next_update,
+        revocation_time=revoked_at,
+        revocation_reason=x509.ReasonFlags.key_compromise if revoked_at else None,
+    )
+    builder = builder.responder_id(ocsp.OCSPResponderEncoding.HASH, ca)
+    return builder.sign(ca_key, hashes.SHA256()).public_bytes(serialization.Encoding.DER)
+
+
+fixtures = {
+    "issuer.der": ca.public_bytes(serialization.Encoding.DER),
+    # no nonce, same bytes as openssl ocsp -no_nonce -reqout
+    "request.der": ocsp.OCSPRequestBuilder()
+    .add_certificate(leaf, ca, hashes.SHA1())
+    .build()
+    .public_bytes(serialization.Encoding.DER),
+    "leaf.der": leaf.public_bytes(serialization.Encoding.DER),
+    "good.der": response(leaf, ocsp.OCSPCertStatus.GOOD, THIS_UPDATE, NEXT_UPDATE),
+    "revoked.der": response(
+        leaf, ocsp.OCSPCertStatus.REVOKED, THIS_UPDATE, NEXT_UPDATE, THIS_UPDATE
+    ),
+    "expired.der": response(leaf, ocsp.OCSPCertStatus.GOOD, EXPIRED_THIS, EXPIRED_NEXT),
+    "other-serial.der": response(other, ocsp.OCSPCertStatus.GOOD, THIS_UPDATE, NEXT_UPDATE),
+    "unauthorized.der": ocsp.OCSPResponseBuilder.build_unsuccessful(
+        ocsp.OCSPResponseStatus.UNAUTHORIZED
+    ).public_bytes(serialization.Encoding.DER),
+}
+for path, der in fixtures.items():
+    with open(path, "wb") as out:
+        out.write(der)